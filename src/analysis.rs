@@ -0,0 +1,887 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::lexer::*;
+
+
+/// Checks that the delimiters in `tokens` are balanced and properly nested.
+/// On failure, returns the type and the line and column of the first
+/// delimiter that is unmatched, or that closes the wrong kind of delimiter
+/// (e.g. `{` closed by `]`). An opening delimiter left unclosed at the end of
+/// the stream is reported at its own position.
+///
+pub fn check_balanced(tokens: &[Token]) -> Result<(), (TokenType, usize, usize)>
+{
+    let mut stack = Vec::new();
+
+    for token in tokens {
+        let type_ = token.token_type();
+
+        if type_.is_open_delimiter() {
+            stack.push(token);
+        } else if type_.is_close_delimiter() {
+            match stack.pop() {
+                Some(open) if open.token_type().matching_close() == Some(type_)
+                    => {},
+                _   => {
+                    let (line, col) = token.pos();
+                    return Err((type_, line, col));
+                },
+            }
+        }
+    }
+    match stack.first() {
+        Some(open) => {
+            let (line, col) = open.pos();
+            Err((open.token_type(), line, col))
+        },
+        None => Ok(()),
+    }
+}
+
+/// Counts the tokens starting on each line. The result is indexed by line
+/// number and runs through the last line with a token, so lines without any
+/// tokens have a count of zero.
+///
+pub fn tokens_per_line(tokens: &[Token]) -> Vec<usize>
+{
+    let mut counts = Vec::new();
+
+    for token in tokens {
+        let (line, _) = token.pos();
+        if line >= counts.len() {
+            counts.resize(line + 1, 0);
+        }
+        counts[line] += 1;
+    }
+    counts
+}
+
+/// Returns the first token whose span starts at or after `byte_offset`, as
+/// for a "next token after the cursor" lookup. `tokens` must be in the order
+/// the lexer produced them.
+///
+pub fn next_token_after<'a, 'input>(tokens      : &'a [Token<'input>], 
+                                    byte_offset : usize) 
+    -> Option<&'a Token<'input>>
+{
+    let i = tokens.partition_point(|t| t.span().start < byte_offset);
+    tokens.get(i)
+}
+
+/// Finds function-call-like patterns: `Identifier` tokens immediately followed
+/// by `(`. Each is returned with the number of arguments between the
+/// parentheses, counted as the non-empty runs of tokens separated by commas
+/// at the top level of the call. A call whose `(` is never closed is skipped.
+///
+pub fn function_calls<'a, 'input>(tokens: &'a [Token<'input>]) 
+    -> Vec<(&'a Token<'input>, usize)>
+{
+    use TokenType::*;
+    let mut calls = Vec::new();
+
+    for (i, pair) in tokens.windows(2).enumerate() {
+        if pair[0].token_type() != Identifier 
+           || pair[1].token_type() != LParen {
+            continue;
+        }
+        let mut depth = 0;
+        let mut args  = 0;
+        let mut empty = true;
+
+        for token in &tokens[i + 1..] {
+            let type_ = token.token_type();
+
+            if type_.is_close_delimiter() {
+                depth -= 1;
+                if depth == 0 {
+                    if !empty {
+                        args += 1;
+                    }
+                    calls.push((&pair[0], args));
+                    break;
+                }
+            } else if depth == 1 && type_ == Comma {
+                if !empty {
+                    args += 1;
+                }
+                empty = true;
+                continue;
+            }
+            if depth > 0 {
+                empty = false;
+            }
+            if type_.is_open_delimiter() {
+                depth += 1;
+            }
+        }
+    }
+    calls
+}
+
+/// Computes a fingerprint of the token stream from each token's type and 
+/// text, ignoring positions, so whitespace-only edits leave it unchanged. The
+/// tokens are hashed in their `normalized_bytes()` form, so line endings 
+/// inside tokens don't matter either. The hash is 64-bit FNV-1a, which is 
+/// stable across builds and platforms, so fingerprints can be stored, e.g. by
+/// a build cache.
+///
+pub fn fingerprint(tokens: &[Token]) -> u64
+{
+    const FNV_OFFSET : u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME  : u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = FNV_OFFSET;
+    let mut feed = |bytes: &[u8]| {
+        for b in bytes {
+            hash ^= u64::from(*b);
+            hash  = hash.wrapping_mul(FNV_PRIME);
+        }
+    };
+    for token in tokens {
+        feed(&token.normalized_bytes());
+        feed(&[0]);
+    }
+    hash
+}
+
+/// Scans `text` and returns the line, column, and message of every lexical
+/// error in it. Recovery is used, so all the errors are found in one pass.
+///
+pub fn lint(text: &str) -> Vec<(usize, usize, String)>
+{
+    let mut lexer  = Lexer::new(text);
+    let mut errors = Vec::new();
+
+    while let Some(result) = lexer.next_recovering() {
+        if let Err(e) = result {
+            let (line, col) = e.pos();
+            errors.push((line, col, e.to_string()));
+        }
+    }
+    errors
+}
+
+/// Splits `tokens` on the separator type `sep`, but only where it appears at
+/// the top level, outside of any `()`, `[]`, or `{}`. The separators aren't
+/// included in the groups. There is always one more group than top-level
+/// separators, so a trailing separator gives an empty last group.
+///
+pub fn split_top_level<'a, 'input>(tokens : &'a [Token<'input>], 
+                                   sep    : TokenType) 
+    -> Vec<&'a [Token<'input>]>
+{
+    let mut groups = Vec::new();
+    let mut depth  = 0usize;
+    let mut start  = 0;
+
+    for (i, token) in tokens.iter().enumerate() {
+        let type_ = token.token_type();
+
+        if type_.is_open_delimiter() {
+            depth += 1;
+        } else if type_.is_close_delimiter() {
+            depth = depth.saturating_sub(1);
+        } else if depth == 0 && type_ == sep {
+            groups.push(&tokens[start..i]);
+            start = i + 1;
+        }
+    }
+    groups.push(&tokens[start..]);
+    groups
+}
+
+/// Which positions an operator can take in an expression, for Pratt parsing.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Fixity
+{
+    /// Before its operand, as in `-x` or `!x`.
+    pub prefix  : bool,
+    /// Between two operands, as in `a - b`.
+    pub infix   : bool,
+    /// After its operand, as in `x!` or `x?`.
+    pub postfix : bool,
+}
+
+impl Fixity
+{
+    pub const NONE    : Fixity = Fixity::new(false, false, false);
+    pub const PREFIX  : Fixity = Fixity::new(true,  false, false);
+    pub const INFIX   : Fixity = Fixity::new(false, true,  false);
+    pub const POSTFIX : Fixity = Fixity::new(false, false, true);
+
+    /// Creates a new `Fixity` from the positions the operator can take.
+    ///
+    pub const fn new(prefix: bool, infix: bool, postfix: bool) -> Self
+    {
+        Fixity { prefix, infix, postfix }
+    }
+}
+
+/// Returns the default fixity of the operator spelled `text`. Operators it
+/// doesn't know have `Fixity::NONE`; `FixityTable` allows overriding these.
+///
+pub fn operator_fixity(text: &str) -> Fixity
+{
+    match text {
+        "+"  | "-"  | "*"  | "&"  => Fixity::new(true,  true,  false),
+        "!"  | "~"                => Fixity::PREFIX,
+        "++" | "--"               => Fixity::new(true,  false, true),
+        "?"                       => Fixity::POSTFIX,
+        "/"  | "%"  | "="  | "==" | "!=" | "<"  | ">"  | "<=" | ">=" 
+      | "&&" | "||" | "|"  | "^"  | "<<" | ">>" | "**" | "??" 
+                                  => Fixity::INFIX,
+        _                         => Fixity::NONE,
+    }
+}
+
+/// Operator fixities: the defaults of `operator_fixity()` with overrides, 
+/// e.g. for a language where `!` is also postfix factorial.
+///
+#[derive(Debug, Clone, Default)]
+pub struct FixityTable
+{
+    overrides : HashMap<String, Fixity>,
+}
+
+impl FixityTable
+{
+    /// Creates a table with just the default fixities.
+    ///
+    pub fn new() -> Self
+    {
+        FixityTable::default()
+    }
+
+    /// Overrides the fixity of the operator spelled `text`.
+    ///
+    pub fn with(mut self, text: &str, fixity: Fixity) -> Self
+    {
+        self.overrides.insert(text.to_string(), fixity);
+        self
+    }
+
+    /// Returns the fixity of the operator spelled `text`.
+    ///
+    pub fn fixity(&self, text: &str) -> Fixity
+    {
+        self.overrides.get(text).copied()
+                      .unwrap_or_else(|| operator_fixity(text))
+    }
+}
+
+/// Extracts the string literals in `text`, e.g. for translation, as the span
+/// of each literal and its unescaped contents. Text after `//` or `#` on a
+/// line is taken to be a comment, so commented-out strings are skipped, as 
+/// are strings with errors.
+///
+pub fn extract_strings(text: &str) -> Vec<(Span, String)>
+{
+    extract_strings_with(text, &["//", "#"])
+}
+
+/// Like `extract_strings()`, but with comments starting at any of 
+/// `comment_markers` instead.
+///
+pub fn extract_strings_with(text            : &str, 
+                            comment_markers : &[&str]) 
+    -> Vec<(Span, String)>
+{
+    let mut lexer   = Lexer::new(text).line_comments(comment_markers);
+    let mut strings = Vec::new();
+
+    while let Some(result) = lexer.next_recovering() {
+        if let Ok(token) = result {
+            if let Some(content) = token.unescaped_text() {
+                strings.push((token.span(), content));
+            }
+        }
+    }
+    strings
+}
+
+/// Returns the tokens whose spans overlap the byte range `start..end`, e.g.
+/// those to re-highlight after an edit. `tokens` must be in the order the 
+/// lexer produced them, so the range can be found by binary search.
+///
+pub fn tokens_in_range<'a, 'input>(tokens : &'a [Token<'input>], 
+                                   start  : usize, 
+                                   end    : usize) 
+    -> &'a [Token<'input>]
+{
+    let first = tokens.partition_point(|t| t.span().end <= start);
+    let last  = tokens.partition_point(|t| t.span().start < end);
+    &tokens[first..last.max(first)]
+}
+
+/// Returns the leading whitespace of line `line` of `text`, counting from 
+/// zero, or `""` if there's no such line. Only spaces, tabs, and other 
+/// non-line-break whitespace count.
+///
+pub fn indentation_of_line(text: &str, line: usize) -> &str
+{
+    match text.split('\n').nth(line) {
+        Some(l) => {
+            let len = l.find(|c: char| !c.is_whitespace() || c == '\r')
+                       .unwrap_or(l.len());
+            &l[..len]
+        },
+        None => "",
+    }
+}
+
+/// Returns the first token on each line that has any, in order, as for 
+/// rendering a gutter.
+///
+pub fn line_first_tokens<'a, 'input>(tokens: &'a [Token<'input>]) 
+    -> Vec<&'a Token<'input>>
+{
+    let mut prev  = None;
+    let mut first = Vec::new();
+
+    for token in tokens {
+        if token.is_first_on_line(prev) {
+            first.push(token);
+        }
+        prev = Some(token);
+    }
+    first
+}
+
+/// Groups `tokens` into lines by the line each starts on, one slice for each
+/// line that has any tokens. `tokens` must be in the order the lexer produced
+/// them, so each line's tokens are contiguous.
+///
+pub fn logical_lines<'a, 'input>(tokens: &'a [Token<'input>]) 
+    -> Vec<&'a [Token<'input>]>
+{
+    let mut lines = Vec::new();
+    let mut start = 0;
+
+    for i in 1..=tokens.len() {
+        if i == tokens.len() || tokens[i].pos().0 != tokens[start].pos().0 {
+            lines.push(&tokens[start..i]);
+            start = i;
+        }
+    }
+    lines
+}
+
+/// Scans `text` and returns it with each token marked: every line of the 
+/// source is followed by a row per token on it, with `^` under the token's
+/// text and its type beside that.
+///
+/// ```text
+/// if x
+/// ^^ Keyword
+///    ^ Identifier
+/// ```
+///
+pub fn annotate(text: &str) -> String
+{
+    let mut lexer  = Lexer::new(text);
+    let mut tokens = Vec::new();
+    while let Some(result) = lexer.next_recovering() {
+        if let Ok(token) = result {
+            tokens.push(token);
+        }
+    }
+    let mut out  = String::new();
+    let mut next = tokens.iter().peekable();
+
+    for (i, line) in text.lines().enumerate() {
+        out.push_str(line);
+        out.push('\n');
+        while let Some(token) = next.next_if(|t| t.pos().0 == i) {
+            let (_, col) = token.pos();
+            let width    = line.chars().skip(col).count()
+                               .min(token.text().chars().count())
+                               .max(1);
+            out.push_str(&format!("{:col$}{} {:?}\n", "", "^".repeat(width),
+                                  token.token_type(), col = col));
+        }
+    }
+    out
+}
+
+/// Renders `tokens` as a flat s-expression, `((Keyword "if") (Identifier "x")
+/// ...)`, with each token's type and quoted text. The format is stable, so
+/// it's handy for diffing token streams, e.g. in tests.
+///
+pub fn sexpr_dump(tokens: &[Token]) -> String
+{
+    let items: Vec<String> = tokens.iter()
+                                   .map(|t| format!("({:?} {:?})", 
+                                                    t.token_type(), t.text()))
+                                   .collect();
+    format!("({})", items.join(" "))
+}
+
+/// Splits `tokens` into top-level expressions, one per line, except that an
+/// expression continues onto the next line while any `()`, `[]`, or `{}` in
+/// it is still open. So `(1 +\n 2)\n(3 * 4)` gives two groups.
+///
+pub fn balanced_groups<'a, 'input>(tokens: &'a [Token<'input>]) 
+    -> Vec<&'a [Token<'input>]>
+{
+    let mut groups = Vec::new();
+    let mut depth  = 0usize;
+    let mut start  = 0;
+
+    for (i, token) in tokens.iter().enumerate() {
+        let type_ = token.token_type();
+
+        if type_.is_open_delimiter() {
+            depth += 1;
+        } else if type_.is_close_delimiter() {
+            depth = depth.saturating_sub(1);
+        }
+        let ends = match tokens.get(i + 1) {
+            Some(next) => depth == 0 && next.pos().0 != token.pos().0,
+            None       => true,
+        };
+        if ends {
+            groups.push(&tokens[start..=i]);
+            start = i + 1;
+        }
+    }
+    groups
+}
+
+/// A minimal grammar for `first_unexpected()`: which token types may follow
+/// which. A type with no rules may be followed by anything.
+///
+#[derive(Debug, Clone, Default)]
+pub struct SimpleGrammar
+{
+    allowed   : HashMap<TokenType, HashSet<TokenType>>,
+    forbidden : HashSet<(TokenType, TokenType)>,
+}
+
+impl SimpleGrammar
+{
+    /// Creates a grammar that allows any token to follow any other.
+    ///
+    pub fn new() -> Self
+    {
+        SimpleGrammar::default()
+    }
+
+    /// Allows the types in `next` to follow `prev`. Once a type has any 
+    /// allowed followers, only those may follow it.
+    ///
+    pub fn allow(mut self, prev: TokenType, next: &[TokenType]) -> Self
+    {
+        self.allowed.entry(prev).or_default().extend(next.iter().copied());
+        self
+    }
+
+    /// Forbids `next` from following `prev`.
+    ///
+    pub fn forbid(mut self, prev: TokenType, next: TokenType) -> Self
+    {
+        self.forbidden.insert((prev, next));
+        self
+    }
+
+    /// Returns `true` if a token of type `next` may follow one of type `prev`.
+    ///
+    pub fn allows(&self, prev: TokenType, next: TokenType) -> bool
+    {
+        !self.forbidden.contains(&(prev, next))
+        && self.allowed.get(&prev).is_none_or(|types| types.contains(&next))
+    }
+}
+
+/// Scans `text` and returns the first token that `grammar` doesn't allow to
+/// follow the token before it, if any. Scanning stops at the first lexical 
+/// error.
+///
+pub fn first_unexpected<'input>(text    : &'input str, 
+                                grammar : &SimpleGrammar) 
+    -> Option<Token<'input>>
+{
+    let mut prev = None;
+
+    for token in Lexer::new(text) {
+        let type_ = token.token_type();
+        if prev.is_some_and(|p| !grammar.allows(p, type_)) {
+            return Some(token);
+        }
+        prev = Some(type_);
+    }
+    None
+}
+
+/// Checks that the spans of `tokens` are in order and don't overlap, as a 
+/// self-check for the lexer and, in particular, for user scanner rules. On
+/// failure, returns the index of the first token that starts before the end
+/// of the one before it.
+///
+pub fn validate_spans(tokens: &[Token]) -> Result<(), usize>
+{
+    for (i, pair) in tokens.windows(2).enumerate() {
+        if pair[1].span().start < pair[0].span().end {
+            return Err(i + 1);
+        }
+    }
+    Ok(())
+}
+
+/// The ANSI colors `highlight_ansi()` uses for each token type, as SGR 
+/// parameters, e.g. `"1;34"` for bold blue: the defaults, with overrides.
+///
+#[derive(Debug, Clone, Default)]
+pub struct ColorMap
+{
+    overrides : HashMap<TokenType, Option<String>>,
+}
+
+impl ColorMap
+{
+    /// Creates a map with just the default colors.
+    ///
+    pub fn new() -> Self
+    {
+        ColorMap::default()
+    }
+
+    /// Sets the color for tokens of type `type_`, or leaves them uncolored if
+    /// `code` is `None`.
+    ///
+    pub fn with(mut self, type_: TokenType, code: Option<&str>) -> Self
+    {
+        self.overrides.insert(type_, code.map(str::to_string));
+        self
+    }
+
+    /// Returns the color for tokens of type `type_`, if they're colored.
+    ///
+    pub fn color(&self, type_: TokenType) -> Option<&str>
+    {
+        use TokenType::*;
+        if let Some(code) = self.overrides.get(&type_) {
+            return code.as_deref();
+        }
+        match type_ {
+            Keyword                                         => Some("1;34"),
+            StringLiteral | ByteStringLiteral | Heredoc 
+          | WordListLiteral | SymbolListLiteral             => Some("32"),
+            NumericLiteral | FloatLiteral | BitStringLiteral => Some("33"),
+            RegexLiteral | Interpolation | Atom             => Some("35"),
+            Operator | Symbol                               => Some("36"),
+            Directive | Parameter                           => Some("1;31"),
+            _                                               => None,
+        }
+    }
+}
+
+/// Returns `source` with ANSI color codes around each of the `tokens`, 
+/// colored by type with the default `ColorMap`. The text between tokens is 
+/// kept as is, so stripping the codes gives back `source`.
+///
+pub fn highlight_ansi(tokens: &[Token], source: &str) -> String
+{
+    highlight_ansi_with(tokens, source, &ColorMap::new())
+}
+
+/// Like `highlight_ansi()`, but with the colors from `colors`.
+///
+pub fn highlight_ansi_with(tokens : &[Token], 
+                           source : &str, 
+                           colors : &ColorMap) -> String
+{
+    let mut out = String::with_capacity(source.len() * 2);
+    let mut pos = 0;
+
+    for token in tokens {
+        let span = token.span();
+        if span.start < pos || span.end > source.len() {
+            continue;
+        }
+        out.push_str(&source[pos..span.start]);
+        let text = &source[span.start..span.end];
+        match colors.color(token.token_type()) {
+            Some(code) if !text.is_empty() => {
+                out.push_str(&format!("\x1b[{}m{}\x1b[0m", code, text));
+            },
+            _ => out.push_str(text),
+        }
+        pos = span.end;
+    }
+    out.push_str(&source[pos..]);
+    out
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn lex(text: &str) -> Vec<Token<'_>>
+    {
+        Lexer::new(text).collect()
+    }
+
+    #[test]
+    fn check_balanced_finds_mismatches()
+    {
+        use TokenType::*;
+        assert_eq!(check_balanced(&lex("{ ( [ ] ) }")), Ok(()));
+        assert_eq!(check_balanced(&lex("{ ]")),    Err((RBracket, 0, 2)));
+        assert_eq!(check_balanced(&lex("x ( a")),  Err((LParen,   0, 2)));
+    }
+
+    #[test]
+    fn tokens_per_line_counts_blank_lines()
+    {
+        assert_eq!(tokens_per_line(&lex("x = 1;\nfoo(y)")), vec![4, 4]);
+        assert_eq!(tokens_per_line(&lex("x\n\ny")),          vec![1, 0, 1]);
+    }
+
+    #[test]
+    fn next_token_after_skips_to_following_token()
+    {
+        let tokens = lex("foo_bar = baz;");
+        let text   = |offset| next_token_after(&tokens, offset)
+                                  .map(Token::text);
+        assert_eq!(text(0),  Some("foo_bar"));
+        assert_eq!(text(3),  Some("="));
+        assert_eq!(text(10), Some("baz"));
+        assert_eq!(text(14), None);
+    }
+
+    #[test]
+    fn function_calls_counts_arguments()
+    {
+        let tokens = lex("f(a, b) + g() + h(x(1, 2), [3, 4],)");
+        let calls  = function_calls(&tokens).into_iter()
+                                            .map(|(t, n)| (t.text(), n))
+                                            .collect::<Vec<_>>();
+        assert_eq!(calls, [("f", 2), ("g", 0), ("h", 2), ("x", 2)]);
+    }
+
+    #[test]
+    fn fingerprint_ignores_layout()
+    {
+        let print = |text| fingerprint(&lex(text));
+        assert_eq!(print("if x { y = 1; }"), print("if   x {\n\ty=1;\n}"));
+        assert_ne!(print("if x { y = 1; }"), print("if x { z = 1; }"));
+        assert_ne!(print("a b"),             print("ab"));
+    }
+
+    #[test]
+    fn lint_collects_every_error()
+    {
+        let found = lint("x = 1;\ny = \"a\\q\";\nz @ 2;");
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].0, found[0].1), (1, 7));
+        assert_eq!((found[1].0, found[1].1), (2, 2));
+    }
+
+    #[test]
+    fn fingerprint_ignores_line_endings()
+    {
+        assert_eq!(fingerprint(&lex("x = \"a\r\nb\";")),
+                   fingerprint(&lex("x = \"a\nb\";")));
+    }
+
+    #[test]
+    fn split_top_level_ignores_nested_separators()
+    {
+        let tokens = lex("a, (b, c), d");
+        let groups = split_top_level(&tokens, TokenType::Comma)
+                         .iter()
+                         .map(|g| g.iter().map(Token::text).collect())
+                         .collect::<Vec<Vec<_>>>();
+        assert_eq!(groups, [vec!["a"], vec!["(", "b", ",", "c", ")"],
+                            vec!["d"]]);
+    }
+
+    #[test]
+    fn operator_fixity_defaults_and_overrides()
+    {
+        let minus = operator_fixity("-");
+        assert!(minus.prefix && minus.infix && !minus.postfix);
+        assert_eq!(operator_fixity("?"),   Fixity::POSTFIX);
+        assert_eq!(operator_fixity("foo"), Fixity::NONE);
+
+        let bang  = Fixity::new(true, false, true);
+        let table = FixityTable::new().with("!", bang);
+        assert!(table.fixity("!").postfix);
+        assert_eq!(table.fixity("?"), Fixity::POSTFIX);
+    }
+
+    #[test]
+    fn extract_strings_skips_comments()
+    {
+        let text  = "a = \"hi \\\"x\\\"\" // \"dead\"\n\
+                     b = r#\"raw\"# # \"gone\"\n";
+        let found = extract_strings(text);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].1, "hi \"x\"");
+        assert_eq!(found[1].1, "raw");
+        let span  = found[0].0;
+        assert_eq!(&text[span.start..span.end], "\"hi \\\"x\\\"\"");
+
+        let found = extract_strings_with("s = \"a\" -- \"b\"\n", &["--"]);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].1, "a");
+    }
+
+    #[test]
+    fn tokens_in_range_finds_overlaps()
+    {
+        let tokens = lex("aa bb cc dd");
+        let texts  = |start, end| tokens_in_range(&tokens, start, end)
+                                      .iter()
+                                      .map(|t| t.text().to_string())
+                                      .collect::<Vec<_>>();
+        assert_eq!(texts(4, 7),         ["bb", "cc"]);
+        assert_eq!(texts(2, 3),         Vec::<String>::new());
+        assert_eq!(texts(0, 100).len(), 4);
+        assert_eq!(texts(5, 3).len(),   0);
+    }
+
+    #[test]
+    fn check_balanced_pairs_optional_index()
+    {
+        assert!(check_balanced(&lex("a?.[i]")).is_ok());
+        assert!(check_balanced(&lex("a?.[i)")).is_err());
+    }
+
+    #[test]
+    fn indentation_of_line_returns_leading_whitespace()
+    {
+        let text = "fn f\n    if x\n\t  \ty\r\n   \r\n";
+        assert_eq!(indentation_of_line(text, 0), "");
+        assert_eq!(indentation_of_line(text, 1), "    ");
+        assert_eq!(indentation_of_line(text, 2), "\t  \t");
+        assert_eq!(indentation_of_line(text, 3), "   ");
+        assert_eq!(indentation_of_line(text, 9), "");
+    }
+
+    #[test]
+    fn line_first_tokens_skips_blank_lines()
+    {
+        let tokens = lex("a b\n\n  c d");
+        let first  = line_first_tokens(&tokens).iter()
+                                                .map(|t| t.text())
+                                                .collect::<Vec<_>>();
+        assert_eq!(first, ["a", "c"]);
+    }
+
+    #[test]
+    fn logical_lines_group_by_line()
+    {
+        let tokens = lex("a b\n\nc\n d e f");
+        let sizes  = logical_lines(&tokens).iter()
+                                           .map(|l| l.len())
+                                           .collect::<Vec<_>>();
+        assert_eq!(sizes, [2, 1, 3]);
+        assert!(logical_lines(&[]).is_empty());
+    }
+
+    #[test]
+    fn annotate_underlines_tokens()
+    {
+        assert_eq!(annotate("if x\n  y = \"a\nb\""),
+                   "if x\n^^ Keyword\n   ^ Identifier\n  y = \"a\n\
+                    \x20 ^ Identifier\n    ^ Operator\n      ^^ StringLiteral\n\
+                    b\"\n");
+    }
+
+    #[test]
+    fn sexpr_dump_of_readme_sample()
+    {
+        let text = r#"if is_true {
+                    foo_var = "hello!";
+                 } else {
+                    bar_var = 0;
+                    foo_var = "String \\ \"with\" escapes.";
+                 }"#;
+        assert_eq!(sexpr_dump(&lex(text)),
+                   concat!(r#"((Keyword "if") (Identifier "is_true") "#,
+                           r#"(LBrace "{") (Identifier "foo_var") "#,
+                           r#"(Operator "=") (StringLiteral "\"hello!\"") "#,
+                           r#"(Semicolon ";") (RBrace "}") (Keyword "else") "#,
+                           r#"(LBrace "{") (Identifier "bar_var") "#,
+                           r#"(Operator "=") (NumericLiteral "0") "#,
+                           r#"(Semicolon ";") (Identifier "foo_var") "#,
+                           r#"(Operator "=") "#,
+                           r#"(StringLiteral "\"String \\\\ \\\"with\\\" "#,
+                           r#"escapes.\"") (Semicolon ";") (RBrace "}"))"#));
+        assert_eq!(sexpr_dump(&[]), "()");
+    }
+
+    #[test]
+    fn balanced_groups_join_lines_inside_brackets()
+    {
+        let sizes = |text| balanced_groups(&lex(text)).iter()
+                                                      .map(|g| g.len())
+                                                      .collect::<Vec<_>>();
+        assert_eq!(sizes("(1+2)\n(3*4)"),        [5, 5]);
+        assert_eq!(sizes("(1 +\n 2) * 3\n4"),    [7, 1]);
+        assert!(balanced_groups(&[]).is_empty());
+    }
+
+    #[test]
+    fn first_unexpected_applies_follow_sets()
+    {
+        use TokenType::*;
+        let grammar = SimpleGrammar::new().forbid(Operator, Operator);
+        let token   = first_unexpected("a + + b", &grammar).unwrap();
+        assert_eq!((token.text(), token.pos()), ("+", (0, 4)));
+        assert!(first_unexpected("a + b", &grammar).is_none());
+
+        let grammar = SimpleGrammar::new().allow(Identifier, &[Operator]);
+        let token   = first_unexpected("a + b c", &grammar).unwrap();
+        assert_eq!(token.text(), "c");
+    }
+
+    #[test]
+    fn validate_spans_finds_overlaps()
+    {
+        let tokens = Lexer::new("a+b (c) \"s\" 1.5").no_space_markers(true)
+                                                   .collect::<Vec<_>>();
+        assert_eq!(validate_spans(&tokens), Ok(()));
+
+        let mut tokens = lex("x abcd");
+        tokens.extend(lex("xy cd").into_iter().skip(1));
+        assert_eq!(validate_spans(&tokens), Err(2));
+    }
+
+    fn strip_ansi(text: &str) -> String
+    {
+        let mut out   = String::new();
+        let mut chars = text.chars();
+        while let Some(ch) = chars.next() {
+            if ch == '\x1b' {
+                chars.by_ref().find(|&c| c == 'm');
+            } else {
+                out.push(ch);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn highlight_ansi_colors_tokens()
+    {
+        let text   = "if x {\n  y = \"s\" + 10; }  ";
+        let tokens = lex(text);
+        let shown  = highlight_ansi(&tokens, text);
+        assert!(shown.starts_with("\x1b[1;34mif\x1b[0m x"));
+        assert!(shown.contains("\x1b[32m\"s\"\x1b[0m"));
+        assert_eq!(strip_ansi(&shown), text);
+
+        let colors = ColorMap::new().with(TokenType::Keyword, Some("31"))
+                                    .with(TokenType::StringLiteral, None);
+        let shown  = highlight_ansi_with(&tokens, text, &colors);
+        assert!(shown.starts_with("\x1b[31mif"));
+        assert!(shown.contains(" \"s\" "));
+    }
+}
@@ -1,10 +1,12 @@
-
 #![allow(dead_code)]
 
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::str::Chars;
 
 
@@ -19,39 +21,312 @@ lazy_static! {
     };
 }
 
+/// Returns `true` for the Unicode bidirectional control characters, which can
+/// make the text displayed differ from the order it's scanned in.
+///
+fn is_bidi_control(ch: char) -> bool
+{
+    matches!(ch, '\u{061C}' | '\u{200E}' | '\u{200F}' 
+               | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Returns `true` for the characters a unit suffix on a number can have.
+///
+fn is_unit_char(ch: char) -> bool
+{
+    ch.is_alphabetic() || ch == '%'
+}
+
+/// If `text` starts with a heredoc opener, `<<TAG`, `<<'TAG'`, or `<<"TAG"`,
+/// returns its length, the tag, and whether it's quoted.
+///
+fn heredoc_tag(text: &str) -> Option<(usize, String, bool)>
+{
+    let rest  = text.strip_prefix("<<")?;
+    let quote = rest.chars().next().filter(|&c| c == '\'' || c == '"');
+    let name  = &rest[quote.map_or(0, char::len_utf8)..];
+    let len   = name.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(name.len());
+    if len == 0 || !name.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        return None;
+    }
+    let tag = &name[..len];
+    match quote {
+        Some(q) if name[len..].starts_with(q) => {
+            Some((len + 4, tag.to_string(), true))
+        },
+        Some(_) => None,
+        None    => Some((len + 2, tag.to_string(), false)),
+    }
+}
+
+/// Finds the line that ends a heredoc body, one that is just `tag`, in `body`,
+/// where lines end at characters `is_break` accepts. Returns the offsets of 
+/// the start of that line, and of the end of the tag on it.
+///
+fn heredoc_end(body: &str, tag: &str, is_break: impl Fn(char) -> bool) 
+    -> Option<(usize, usize)>
+{
+    let mut start = 0;
+    loop {
+        let line = &body[start..];
+        let line = &line[..line.find(&is_break).unwrap_or(line.len())];
+        if line.strip_suffix('\r').unwrap_or(line) == tag {
+            return Some((start, start + tag.len()));
+        }
+        match body[start + line.len()..].chars().next() {
+            Some(ch) => start += line.len() + ch.len_utf8(),
+            None     => return None,
+        }
+    }
+}
+
+/// Returns `true` if `text` starts with an opening or closing tag, i.e. a `<`
+/// followed by a tag name or by `/` and a tag name.
+///
+fn starts_tag(text: &str) -> bool
+{
+    let name = text.strip_prefix("</").or_else(|| text.strip_prefix('<'));
+    name.is_some_and(|rest| rest.starts_with(char::is_alphabetic))
+}
+
+/// Returns the length of the tag or attribute name at the start of `text`. 
+/// Names can contain `-`, `:`, and `.` as well as letters, digits, and `_`.
+///
+fn tag_name_len(text: &str) -> usize
+{
+    text.find(|c: char| !(c.is_alphanumeric() || "_-:.".contains(c)))
+        .unwrap_or(text.len())
+}
+
+/// Replaces each lone carriage return in `text`, one not followed by `\n`, 
+/// with `\n`.
+///
+fn lone_cr_to_lf(text: &str) -> String
+{
+    let mut out   = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    
+    while let Some(ch) = chars.next() {
+        if ch == '\r' && chars.peek() != Some(&'\n') {
+            out.push('\n');
+        } else {
+            out.push(ch);
+        }
+    }
+    out
+}
+
 /// Various token types. This populates the `Token.type_` field.
 ///
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TokenType 
 {
     Keyword,
     Identifier,
     StringLiteral,
+    ByteStringLiteral,
+    WordListLiteral,
+    SymbolListLiteral,
     NumericLiteral,
+    FloatLiteral,
+    BitStringLiteral,
+    RegexLiteral,
     Operator,
     LParen,
     RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Comma,
+    Dot,
+    DotDot,
+    DotDotDot,
+    DotDotEq,
     Semicolon,
+    At,
+    Atom,
+    Parameter,
+    Verbatim,
+    /// A zero-width marker between two tokens with no whitespace between 
+    /// them; see `Lexer::no_space_markers()`.
+    NoSpace,
+    /// The start of an HTML/XML opening tag with its name, e.g. `<a`, in 
+    /// `html_tags()` mode. Its attributes and a `TagEnd` or `TagSelfClose`
+    /// follow.
+    TagOpen,
+    /// The `>` that ends an opening tag.
+    TagEnd,
+    /// The `/>` that ends a self-closing tag.
+    TagSelfClose,
+    /// A closing tag, e.g. `</a>`.
+    TagClose,
+    /// The name of an attribute in a tag. A `=` and a `StringLiteral` value
+    /// may follow.
+    AttrName,
+    /// Text between tags.
+    Text,
+    /// A word made of operator characters, like `+` or `<=`, in 
+    /// `lisp_symbols()` mode.
+    Symbol,
+    /// The indentation at the start of a line, with tabs expanded to spaces,
+    /// in `expand_tabs()` mode.
+    Whitespace,
+    /// The start of a heredoc, e.g. `<<EOF` or `<<'EOF'`, in `heredocs()` 
+    /// mode. Its body follows on the next line.
+    HeredocStart,
+    /// Heredoc body text: all of it for a quoted tag, or the text between
+    /// interpolations otherwise.
+    Heredoc,
+    /// An interpolation in a heredoc body, `$name` or `${...}`.
+    Interpolation,
+    /// The tag that ends a heredoc, on a line of its own.
+    HeredocEnd,
+    /// A comment that's a directive, e.g. `# noqa`; see `Lexer::directives()`.
+    Directive,
+    /// A bare `?`, as in `x?` or a ternary.
+    Question,
+    /// A `:`.
+    Colon,
+    /// The `:` of a ternary, `a ? b : c`, in `ternary_colons()` mode.
+    TernaryColon,
+    /// The optional chaining `?.`, as in `a?.b`.
+    QuestionDot,
+    /// The optional indexing `?.[`, as in `a?.[i]`. It's an opening 
+    /// delimiter, closed by `]`.
+    QuestionDotBracket,
+    /// A token kind defined by a user scanner rule; see `Lexer::with_rules()`.
+    Custom(&'static str),
+}
+
+impl TokenType
+{
+    /// Returns `true` for the opening delimiters, `(`, `{`, `[`, and `?.[`.
+    ///
+    pub fn is_open_delimiter(self) -> bool
+    {
+        use TokenType::*;
+        matches!(self, LParen | LBrace | LBracket | QuestionDotBracket)
+    }
+    
+    /// Returns `true` for the closing delimiters, `)`, `}`, and `]`.
+    ///
+    pub fn is_close_delimiter(self) -> bool
+    {
+        use TokenType::*;
+        matches!(self, RParen | RBrace | RBracket)
+    }
+    
+    /// For a closing delimiter, returns the opening delimiter it matches.
+    ///
+    pub fn matching_open(self) -> Option<TokenType>
+    {
+        use TokenType::*;
+        match self {
+            RParen   => Some(LParen),
+            RBrace   => Some(LBrace),
+            RBracket => Some(LBracket),
+            _        => None,
+        }
+    }
+    
+    /// For an opening delimiter, returns the closing delimiter it matches.
+    ///
+    pub fn matching_close(self) -> Option<TokenType>
+    {
+        use TokenType::*;
+        match self {
+            LParen                        => Some(RParen),
+            LBrace                        => Some(RBrace),
+            LBracket | QuestionDotBracket => Some(RBracket),
+            _                             => None,
+        }
+    }
+}
+
+/// A range of byte offsets, `start..end`, into the text being scanned.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span
+{
+    pub start : usize,
+    pub end   : usize,
+}
+
+impl Span
+{
+    /// Creates a new `Span` covering `start..end`.
+    ///
+    pub fn new(start: usize, end: usize) -> Self
+    {
+        Span { start, end }
+    }
+    
+    /// Returns the number of bytes covered by the span.
+    ///
+    pub fn len(&self) -> usize
+    {
+        self.end - self.start
+    }
+    
+    /// Returns `true` if the span covers no bytes.
+    ///
+    pub fn is_empty(&self) -> bool
+    {
+        self.start == self.end
+    }
 }
 
-/// Everything returned by the lexer is a Token.
+/// Everything returned by the lexer is a Token. A token can carry a `data`
+/// payload computed as it's scanned; see `Lexer::with_token_data()`.
 ///
 #[derive(Debug)]
-pub struct Token<'input> 
+pub struct Token<'input, T = ()> 
 {
     type_ : TokenType,
-    text  : &'input str,
+    text  : Cow<'input, str>,
     line  : usize,
     col   : usize,
+    span  : Span,
+    data  : T,
 }
 
 impl<'input> Token<'input>
 {
     /// Creates a new `Token`. Only the lexer creates these.
     ///
-    fn new(type_: TokenType, text: &'input str, line: usize, col: usize) -> Self
+    fn new(type_ : TokenType, 
+           text  : &'input str, 
+           line  : usize, 
+           col   : usize, 
+           span  : Span) -> Self
+    {
+        Token { type_, text: Cow::Borrowed(text), line, col, span, data: () }
+    }
+}
+
+impl<'input, T> Token<'input, T>
+{
+    /// Returns the token's payload.
+    ///
+    pub fn data(&self) -> &T
+    {
+        &self.data
+    }
+    
+    /// Returns the token with its payload replaced by `data`.
+    ///
+    pub fn with_data<U>(self, data: U) -> Token<'input, U>
     {
-        Token { type_, text, line, col }
+        Token { 
+            type_ : self.type_, 
+            text  : self.text, 
+            line  : self.line, 
+            col   : self.col, 
+            span  : self.span, 
+            data,
+        }
     }
     
     /// Returns the token type.
@@ -65,7 +340,7 @@ impl<'input> Token<'input>
     ///
     pub fn text(&self) -> &str
     {
-        self.text
+        &self.text
     }
     
     /// Returns the line and column offsets for the start of the token text.
@@ -74,6 +349,129 @@ impl<'input> Token<'input>
     {
         (self.line, self.col)
     }
+    
+    /// Returns the line and column of the token, counting from zero. This is
+    /// the same as `pos()`, but explicit about the convention.
+    ///
+    pub fn pos_zero_based(&self) -> (usize, usize)
+    {
+        (self.line, self.col)
+    }
+    
+    /// Returns the line and column of the token, counting from one, as 
+    /// editors usually show them.
+    ///
+    pub fn pos_one_based(&self) -> (usize, usize)
+    {
+        (self.line + 1, self.col + 1)
+    }
+    
+    /// Returns the byte range of the token text within the scanned text.
+    ///
+    pub fn span(&self) -> Span
+    {
+        self.span
+    }
+    
+    /// For a `Parameter` token, returns the parameter's name or number, i.e.
+    /// the text after the sigil. A positional `?` has none.
+    ///
+    pub fn parameter_name(&self) -> Option<&str>
+    {
+        match self.type_ {
+            TokenType::Parameter if self.text.len() > 1 => {
+                Some(&self.text[1..])
+            },
+            _ => None,
+        }
+    }
+    
+    /// Returns a byte representation of the token for hashing: a tag for the
+    /// type, a zero byte, then the text with `\r\n` line breaks normalized to
+    /// `\n`. The same logical token gives the same bytes whatever the line 
+    /// ending convention of the text it came from.
+    ///
+    pub fn normalized_bytes(&self) -> Vec<u8>
+    {
+        let mut bytes = format!("{:?}", self.type_).into_bytes();
+        bytes.push(0);
+        bytes.extend(self.text.replace("\r\n", "\n").bytes());
+        bytes
+    }
+    
+    /// For a `StringLiteral`, returns its contents with the quotes removed 
+    /// and any escapes resolved. Raw strings and `%q` literals are returned
+    /// as written, without their delimiters. Other tokens have none.
+    ///
+    pub fn unescaped_text(&self) -> Option<String>
+    {
+        if self.type_ != TokenType::StringLiteral {
+            return None;
+        }
+        let text = &*self.text;
+        if let Some(rest) = text.strip_prefix('r') {
+            let hashes = rest.len() - rest.trim_start_matches('#').len();
+            return Some(rest[hashes + 1..rest.len() - hashes - 1].to_string());
+        }
+        if let Some(rest) = text.strip_prefix("%q") {
+            let mut chars = rest.chars();
+            chars.next();
+            chars.next_back();
+            return Some(chars.as_str().to_string());
+        }
+        let mut out     = String::with_capacity(text.len());
+        let mut escaped = false;
+        for ch in text[1..text.len() - 1].chars() {
+            if ch == '\\' && !escaped {
+                escaped = true;
+            } else {
+                out.push(ch);
+                escaped = false;
+            }
+        }
+        Some(out)
+    }
+    
+    /// Returns `true` if this token is the first on its line, given the token
+    /// before it, `prev`, if any.
+    ///
+    pub fn is_first_on_line(&self, prev: Option<&Token<'_, T>>) -> bool
+    {
+        prev.is_none_or(|p| p.line != self.line)
+    }
+    
+    /// For a number with a unit suffix, e.g. `10px`, returns the unit. See 
+    /// `Lexer::units()`.
+    ///
+    pub fn unit(&self) -> Option<&str>
+    {
+        use TokenType::*;
+        match self.type_ {
+            NumericLiteral | FloatLiteral => {
+                self.text.find(is_unit_char).map(|i| &self.text[i..])
+            },
+            _ => None,
+        }
+    }
+    
+    /// Returns `true` if `other` has the same type and text as this token,
+    /// regardless of where either appears.
+    ///
+    pub fn same_lexeme<U>(&self, other: &Token<'_, U>) -> bool
+    {
+        self.type_ == other.type_ && self.text == other.text
+    }
+}
+
+/// Writes the compact form of a token, `<line>:<col> <type> <text>`, with the
+/// text quoted and escaped as in `Debug` output.
+///
+impl<'input, T> fmt::Display for Token<'input, T>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result 
+    {
+        write!(f, "{}:{} {:?} {:?}", self.line, self.col, self.type_, self.text)
+    }
 }
 
 /// An enum that implements Error that represents the various types of error 
@@ -82,25 +480,264 @@ impl<'input> Token<'input>
 #[derive(Clone, Debug)]
 pub enum LexerError
 {
-    //GeneralError      { message: String },
-    UnrecognizedStart { message: String, line: usize, col: usize },
-    InvalidEscape     { message: String, line: usize, col: usize }
+    //GeneralError       { message: String },
+    UnrecognizedStart  { message: String, line: usize, col: usize, span: Span },
+    InvalidEscape      { message: String, line: usize, col: usize, span: Span },
+    Unterminated       { message: String, line: usize, col: usize, span: Span },
+    IdentifierTooLong  { message: String, line: usize, col: usize, span: Span },
+    LoneCarriageReturn { message: String, line: usize, col: usize, span: Span },
+    LeadingZero        { message: String, line: usize, col: usize, span: Span },
+    LineTooLong        { line: usize, col: usize, limit: usize, span: Span },
+    TabCharacter       { line: usize, col: usize, span: Span },
+    UnknownUnit        { message: String, line: usize, col: usize, span: Span },
+    NestingTooDeep     { line: usize, col: usize, limit: usize, span: Span },
+    BidiControl        { line: usize, col: usize, codepoint: char, span: Span },
+    Disallowed         { type_: TokenType, line: usize, col: usize, 
+                         span: Span },
 }
 impl Error for LexerError { }
 
+impl LexerError
+{
+    /// Returns the line and column offsets where the error occurred.
+    ///
+    pub fn pos(&self) -> (usize, usize)
+    {
+        use LexerError::*;
+        match self {
+            UnrecognizedStart  { line, col, .. } => (*line, *col),
+            InvalidEscape      { line, col, .. } => (*line, *col),
+            Unterminated       { line, col, .. } => (*line, *col),
+            IdentifierTooLong  { line, col, .. } => (*line, *col),
+            LoneCarriageReturn { line, col, .. } => (*line, *col),
+            LeadingZero        { line, col, .. } => (*line, *col),
+            LineTooLong        { line, col, .. } => (*line, *col),
+            TabCharacter       { line, col, .. } => (*line, *col),
+            UnknownUnit        { line, col, .. } => (*line, *col),
+            NestingTooDeep     { line, col, .. } => (*line, *col),
+            BidiControl        { line, col, .. } => (*line, *col),
+            Disallowed         { line, col, .. } => (*line, *col),
+        }
+    }
+    
+    /// Returns the byte range of the text the error is about.
+    ///
+    pub fn span(&self) -> Span
+    {
+        use LexerError::*;
+        match self {
+            UnrecognizedStart  { span, .. } => *span,
+            InvalidEscape      { span, .. } => *span,
+            Unterminated       { span, .. } => *span,
+            IdentifierTooLong  { span, .. } => *span,
+            LoneCarriageReturn { span, .. } => *span,
+            LeadingZero        { span, .. } => *span,
+            LineTooLong        { span, .. } => *span,
+            TabCharacter       { span, .. } => *span,
+            UnknownUnit        { span, .. } => *span,
+            NestingTooDeep     { span, .. } => *span,
+            BidiControl        { span, .. } => *span,
+            Disallowed         { span, .. } => *span,
+        }
+    }
+    
+    /// Returns the lines of `source` around the error for display: up to 
+    /// `lines_before` lines before the error's line and `lines_after` after,
+    /// each prefixed with its line number (counting from one, for people), 
+    /// the error's line marked with `>` and followed by a `^` under the 
+    /// error's column.
+    ///
+    /// ```text
+    ///   2 | let a = 1;
+    /// > 3 | let b = $;
+    ///     |         ^
+    ///   4 | let c = 3;
+    /// ```
+    ///
+    pub fn context(&self, 
+                   source       : &str, 
+                   lines_before : usize, 
+                   lines_after  : usize) -> String
+    {
+        let (line, col) = self.pos();
+        let first = line.saturating_sub(lines_before);
+        let last  = line + lines_after;
+        let width = (last + 1).to_string().len();
+        let mut out = String::new();
+        
+        for (i, text) in source.lines().enumerate().skip(first) {
+            if i > last {
+                break;
+            }
+            let mark = if i == line { '>' } else { ' ' };
+            out.push_str(&format!("{} {:>w$} | {}\n", mark, i + 1, text, 
+                                  w = width));
+            if i == line {
+                out.push_str(&format!("  {:w$} | {:c$}^\n", "", "", 
+                                      w = width, c = col));
+            }
+        }
+        out
+    }
+}
+
 impl fmt::Display for LexerError 
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result 
     {
         use LexerError::*;
         match self {
-            //GeneralError      { message     } => write!(f, "{}", message),
-            UnrecognizedStart { message, .. } => write!(f, "{}", message),
-            InvalidEscape     { message, .. } => write!(f, "{}", message),
+            //GeneralError       { message     } => write!(f, "{}", message),
+            UnrecognizedStart  { message, .. } => write!(f, "{}", message),
+            InvalidEscape      { message, .. } => write!(f, "{}", message),
+            Unterminated       { message, .. } => write!(f, "{}", message),
+            IdentifierTooLong  { message, .. } => write!(f, "{}", message),
+            LoneCarriageReturn { message, .. } => write!(f, "{}", message),
+            LeadingZero        { message, .. } => write!(f, "{}", message),
+            UnknownUnit        { message, .. } => write!(f, "{}", message),
+            BidiControl        { codepoint, .. } => {
+                write!(f, "Unicode bidirectional control character, U+{:04X}.",
+                       *codepoint as u32)
+            },
+            Disallowed         { type_,   .. } => {
+                write!(f, "Token type {:?} is not allowed.", type_)
+            },
+            LineTooLong        { line, limit, .. } => {
+                write!(f, "Line {} is longer than {} characters.", 
+                       line, limit)
+            },
+            TabCharacter       { .. } => write!(f, "Tab character."),
+            NestingTooDeep     { limit, .. } => {
+                write!(f, "Brackets nested more than {} deep.", limit)
+            },
+        }
+    }
+}
+
+/// Things worth pointing out about the text that don't stop the lexer. These
+/// accumulate in the lexer and are available from `.warnings()`.
+///
+#[derive(Clone, Debug)]
+pub enum LexerWarning
+{
+    TrailingComma       { line: usize, col: usize },
+    IdentifierTruncated { line: usize, col: usize, limit: usize },
+    IdentifierCase      { line: usize, col: usize, convention: Convention },
+}
+
+impl fmt::Display for LexerWarning 
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result 
+    {
+        use LexerWarning::*;
+        match self {
+            TrailingComma { .. } => {
+                write!(f, "Trailing comma before closing delimiter.")
+            },
+            IdentifierTruncated { limit, .. } => {
+                write!(f, "Identifier truncated to {} characters.", limit)
+            },
+            IdentifierCase { convention, .. } => {
+                write!(f, "Identifier doesn't follow the {:?} convention.", 
+                       convention)
+            },
+        }
+    }
+}
+
+/// Progress through an annotation, `@Name(args)`, in annotations mode. The
+/// offsets are where the annotation starts and, once known, ends.
+///
+#[derive(Debug, Clone, Copy)]
+enum Annotation
+{
+    Idle,
+    Sigil(usize),
+    Name(usize, usize),
+    Args(usize, usize),
+}
+
+/// What the lexer does with a lone carriage return, one not followed by a
+/// `\n`, inside a string literal. See `Lexer::lone_cr()`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrPolicy
+{
+    /// Keep it as is. This is the default.
+    Preserve,
+    /// Replace it with `\n` in the token text, and count it as a line break.
+    Normalize,
+    /// Stop with a `LexerError::LoneCarriageReturn`.
+    Error,
+}
+
+/// Identifier naming conventions that can be checked with 
+/// `Lexer::identifier_convention()`. Leading and trailing underscores are 
+/// allowed by all of them.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
+pub enum Convention
+{
+    /// `snake_case`: no uppercase letters.
+    SnakeCase,
+    /// `SCREAMING_SNAKE_CASE`: no lowercase letters.
+    ScreamingSnakeCase,
+    /// `camelCase`: starts lowercase, no underscores.
+    CamelCase,
+    /// `PascalCase`: starts uppercase, no underscores.
+    PascalCase,
+}
+
+impl Convention
+{
+    /// Returns `true` if the identifier `text` follows the convention.
+    ///
+    pub fn matches(self, text: &str) -> bool
+    {
+        use Convention::*;
+        let name  = text.trim_matches('_');
+        let first = name.chars().next();
+        match self {
+            SnakeCase          => !name.chars().any(char::is_uppercase),
+            ScreamingSnakeCase => !name.chars().any(char::is_lowercase),
+            CamelCase          => !name.contains('_') 
+                                  && !first.is_some_and(char::is_uppercase),
+            PascalCase         => !name.contains('_') 
+                                  && !first.is_some_and(char::is_lowercase),
         }
     }
 }
 
+/// The styles of SQL parameter placeholder that can be enabled with 
+/// `Lexer::parameter_style()`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParamStyle
+{
+    /// Positional, `?`.
+    Question,
+    /// Numbered, `$1`.
+    Numbered,
+    /// Named with a colon, `:name`.
+    Named,
+    /// Named with an at sign, `@name`.
+    At,
+}
+
+/// What the lexer does with an identifier that exceeds the maximum length set
+/// by `Lexer::max_identifier_len()`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthPolicy
+{
+    /// Stop with a `LexerError::IdentifierTooLong`.
+    Error,
+    /// Produce the identifier cut down to the maximum length, and record an
+    /// `IdentifierTruncated` warning.
+    Truncate,
+}
+
 /// When the lexer stops producing tokens, its status will be a variant of
 /// this enum.
 ///
@@ -110,274 +747,3833 @@ pub enum LexerStatus
     Okay,
     EndOfStream,
     Error(LexerError),
+    TooManyErrors,
 }
 
-/// Represents the lexer and its state. Keeps track of position information in
-/// the text being scanned and produces `Token`'s.
+/// Records how byte offsets in preprocessed text map back to the original 
+/// source, so tokens scanned from the preprocessed text can report positions
+/// in the original. A fresh map is the identity; each mapping added says that
+/// from a processed offset onward, offsets advance in step with the original
+/// starting at the given original offset.
 ///
-pub struct Lexer<'input>
+#[derive(Debug, Clone)]
+pub struct SourceMap<'input>
 {
-    status  : LexerStatus,
-    text    : &'input str,
-    chars   : Chars<'input>,
-    buf     : VecDeque<char>,
-    offset  : usize,
-    line    : usize,
-    col     : usize,
+    original    : &'input str,
+    line_starts : Vec<usize>,
+    segments    : Vec<(usize, usize)>,
 }
 
-impl<'input> Lexer<'input>
+impl<'input> SourceMap<'input>
 {
-    /// Creates a new lexer to tokenize the given `text`.
+    /// Creates an identity map over the `original` source text.
     ///
-    pub fn new(text: &'input str) -> Self
+    pub fn new(original: &'input str) -> Self
     {
-        Lexer { 
-            status  : LexerStatus::Okay, 
-            text, 
-            chars   : text.chars(),
-            buf     : VecDeque::new(),
-            offset  : 0,
-            line    : 0,
-            col     : 0,
-        }
+        let line_starts = std::iter::once(0)
+            .chain(original.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        SourceMap { original, line_starts, segments: vec![(0, 0)] }
     }
     
-    /// Returns the status of the lexer. This can be called after the lexer
-    /// stops producing tokens to find out if it parsed the full text or
-    /// encounted an error along the way.
+    /// Records that `processed` in the preprocessed text corresponds to 
+    /// `original` in the source, and that subsequent offsets follow on from
+    /// there. Mappings must be added in increasing `processed` order.
     ///
-    pub fn status(&self) -> &LexerStatus
+    pub fn add_mapping(&mut self, processed: usize, original: usize)
     {
-        &self.status
+        self.segments.push((processed, original));
     }
     
-    /// Produces the next character to process as `Some(<ch>)`, or `None` if 
-    /// finished.
+    /// Records that `len` bytes of the original were removed from the text at
+    /// the `processed` offset.
     ///
-    fn next_char(&mut self) -> Option<char>
+    pub fn add_removal(&mut self, processed: usize, len: usize)
     {
-        use LexerStatus::*;
-        match self.status {
-            Okay => {
-                if !self.buf.is_empty() {
-                    self.buf.pop_front()
-                } else {
-                    let next = self.chars.next();
-                    if next.is_none() {
-                        self.status = EndOfStream;
-                    }
-                    next
-                }
-            },
-            _ => { None }
-        }
+        let original = self.to_original(processed) + len;
+        self.add_mapping(processed, original);
     }
     
-    /// Look ahead `ahead` number of characters.
+    /// Maps an offset in the preprocessed text to the original source.
     ///
-    fn look_ahead(&mut self, ahead: usize) -> Option<char>
+    pub fn to_original(&self, processed: usize) -> usize
     {
-        let mut end = false;
-        let     len = self.buf.len();
-        
-        for _ in len..ahead {
-            match self.chars.next() {
-                Some(ch) => { self.buf.push_back(ch); },
-                None     => { end = true; break; },
-            }       
-        }
-        if !end && ahead > 0 {
-            Some(self.buf[ahead - 1])
-        } else {
-            None
-        }
+        let i = match self.segments.binary_search_by_key(&processed, |s| s.0) {
+            Ok(i)  => i,
+            Err(i) => i - 1,
+        };
+        let (p, o) = self.segments[i];
+        o + (processed - p)
     }
     
-    /// Put a single character back in the text stream at the front. The 
-    /// `next_char()` method will return this character on its next invocation.
+    /// Returns the line and column offsets of an original source offset.
     ///
-    fn put_back(&mut self, ch: char) 
+    pub fn original_pos(&self, original: usize) -> (usize, usize)
     {
-        self.buf.push_front(ch);
+        let line = match self.line_starts.binary_search(&original) {
+            Ok(i)  => i,
+            Err(i) => i - 1,
+        };
+        let start = self.line_starts[line];
+        let end   = original.min(self.original.len());
+        (line, self.original[start..end].chars().count())
+    }
+}
+
+/// A simple arena that owns copies of text for lexers to scan. Tokens from a
+/// lexer created by `Lexer::in_arena()` reference the arena's memory, so they
+/// stay valid after the original text is gone, and lexing many small sources
+/// allocates once per source rather than once per owned token.
+///
+#[derive(Default)]
+pub struct Arena
+{
+    chunks : RefCell<Vec<Box<str>>>,
+}
+
+impl Arena
+{
+    /// Creates an empty arena.
+    ///
+    pub fn new() -> Self
+    {
+        Arena::default()
     }
     
-    /// Produces the next token for the lexer as `Some(<token>)`. `None` is 
-    /// returned if the lexer reached the end of the input text or an error
-    /// occurred, which can be checked using `.status()`.
+    /// Copies `text` into the arena, returning the copy.
     ///
-    fn next_token(&mut self) -> Option<Token<'input>>
+    pub fn alloc_str(&self, text: &str) -> &str
     {
-        use TokenType::*;
-        use LexerError::*;
-        use LexerStatus::*;
-        let mut ret = None;
+        let chunk: Box<str> = text.into();
+        let ptr = &*chunk as *const str;
+        self.chunks.borrow_mut().push(chunk);
         
-        'outer: while let Some(ch) = self.next_char() {
-            match ch {
-                '\n' => {
-                    self.offset += 1;
-                    self.line   += 1;
-                    self.col     = 0;
-                },
-                ' ' | '\t'| '\r' => {
-                    self.offset += 1;
-                    self.col    += 1;
-                },
-                '{'  => {
-                    ret = Some(Token::new(LParen, "{", self.line, self.col));
-                    self.offset += 1;
-                    self.col    += 1;
-                    break 'outer;
-                },
-                '}'  => {
-                    ret = Some(Token::new(RParen, "}", self.line, self.col));
-                    self.offset += 1;
-                    self.col    += 1;
-                    break 'outer;
-                },
-                '+' | '-' | '*' | '/' | '=' => {
-                    // Operator.
-                    
-                    let off = self.offset;
-                    ret = Some(Token::new(Operator, 
-                                          &self.text[off..off + 1], 
-                                          self.line, 
-                                          self.col));
-                    self.offset += 1;
-                    self.col    += 1;
-                    break 'outer;
-                },
-                ';' => {
-                    ret = Some(Token::new(Semicolon, ";", self.line, self.col));
-                    self.offset += 1;
-                    self.col    += 1;
-                    break 'outer;
-                },
-                '"' => {
-                    // StringLiteral.
-                    
-                    let mut escaped = false;
-                    let mut end     = 1;
-                    
-                    while let Some(ch) = self.next_char() {
-                        end += 1;
-                        match ch {
-                            '\\' => { 
-                                if !escaped {
-                                    if let Some(la) = self.look_ahead(1) {
-                                    if la != '"' && la != '\\' {
-                                        self.status = Error(
-                                            InvalidEscape { 
-                                                message: format!(
-                                                    "Invalid escape in string, \
-                                                    \"\\{}\".", la),
-                                                line: self.line,
-                                                col : self.col + end,
-                                         });
-                                         break 'outer;
-                                    }}
-                                    escaped = true;
-                                } else { escaped = false; }
-                            },
-                            '"'  => {
-                                if !escaped {
-                                    let off  = self.offset;
-                                    let text = &self.text[off..off + end];
-                                    ret = Some(Token::new(StringLiteral,
-                                                          text,
-                                                          self.line,
-                                                          self.col));
-                                    self.offset += end;
-                                    self.col    += end;
-                                    break 'outer;
-                                } else { escaped = false; }
-                            },
-                            _    => { escaped = false; },
-                        }
-                    }
-                },
-                'a'..='z' | 'A'..='Z' | '_' => {
-                    // Identifier or Keyword.
-                    
-                    let mut end = 1;
-                    while let Some(ch) = self.next_char() {
-                        end += 1;
-                        match ch {
-                            'a'..='z' | 'A'..='Z' | '_' => {},
-                            _ => {
-                                self.put_back(ch);
-                                end -= 1;
-
-                                let off   = self.offset;
-                                let text  = &self.text[off..off + end];
-                                let is_kw = KEYWORDS.contains(text);
-                                let token = if is_kw { Keyword    } 
-                                            else     { Identifier };
-                                ret = Some(Token::new(token,
-                                                      text,
-                                                      self.line,
-                                                      self.col));
-                                self.offset += end;
-                                self.col    += end;
-                                break 'outer; 
-                            }
-                        }
-                    }
-                },
-                '0'..='9' => {
-                    // NumericLiteral.
-                    
-                    let mut end = 1;
-                    while let Some(ch) = self.next_char() {
-                        end += 1;
-                        match ch {
-                            '0'..='9' => {},
-                            _ => {
-                                self.put_back(ch);
-                                end -= 1;
-
-                                let off   = self.offset;
-                                let text  = &self.text[off..off + end];
-                                ret = Some(Token::new(NumericLiteral,
-                                                      text,
-                                                      self.line,
-                                                      self.col));
-                                self.offset += end;
-                                self.col    += end;
-                                break 'outer;
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    // Uh oh!
-                    
-                    self.status = Error(
-                        UnrecognizedStart { 
-                            message: format!("Unrecognized start \
-                                             character, '{}'.", 
-                                             ch),
-                            line: self.line,
-                            col : self.col,
-                     });
-                     break 'outer;
-                },
-            }
-        }
-        ret
+        // SAFETY: `ptr` points at the heap memory owned by the boxed chunk,
+        // not into the `Vec`, so it doesn't move when the `Vec` grows. Chunks
+        // are never removed or mutated, and there's no `&mut` access to them,
+        // so the memory stays valid and unaliased by writes for as long as 
+        // the arena is borrowed, which is the lifetime of the result.
+        unsafe { &*ptr }
     }
 }
-             
-/// Enables the lexer to be used as an iterator in loops.
-/// 
-impl<'input> Iterator for Lexer<'input>
+
+/// A user scanner rule for `Lexer::with_rules()`. The first function is a
+/// predicate on the first character of a token; if it returns `true`, the 
+/// second function scans the token.
+///
+pub type ScanRule<'input> = (fn(char) -> bool, 
+                             fn(&mut Scanner<'_, 'input>) -> Token<'input>);
+
+/// The view of the lexer given to a user scanner rule. It's positioned just 
+/// after the first character of the token and keeps track of how much text 
+/// the rule consumes; that text becomes the token.
+///
+pub struct Scanner<'a, 'input>
 {
-    type Item = Token<'input>;
+    lexer : &'a mut Lexer<'input>,
+    len   : usize,
+}
+
+impl<'a, 'input> Scanner<'a, 'input>
+{
+    /// Consumes and returns the next character, or `None` at the end of the 
+    /// text.
+    ///
+    pub fn next_char(&mut self) -> Option<char>
+    {
+        let next = self.lexer.next_char();
+        if let Some(ch) = next {
+            self.len += ch.len_utf8();
+        }
+        next
+    }
     
-    fn next(&mut self) -> Option<Self::Item>
+    /// Look ahead `ahead` number of characters without consuming them.
+    ///
+    pub fn look_ahead(&mut self, ahead: usize) -> Option<char>
+    {
+        self.lexer.look_ahead(ahead)
+    }
+    
+    /// Puts back `ch`, which should be the last character consumed.
+    ///
+    pub fn put_back(&mut self, ch: char)
+    {
+        self.len -= ch.len_utf8();
+        self.lexer.put_back(ch);
+    }
+    
+    /// Returns the text consumed so far for the token.
+    ///
+    pub fn text(&self) -> &'input str
+    {
+        let off = self.lexer.offset;
+        &self.lexer.text[off..off + self.len]
+    }
+    
+    /// Creates a token of type `type_` for the text consumed so far.
+    ///
+    pub fn token(&self, type_: TokenType) -> Token<'input>
     {
-        self.next_token()
+        self.lexer.token(type_, self.text())
     }
 }
 
+/// Represents the lexer and its state. Keeps track of position information in
+/// the text being scanned and produces `Token`'s.
+///
+pub struct Lexer<'input>
+{
+    status  : LexerStatus,
+    text    : &'input str,
+    ascii   : bool,
+    chars   : Chars<'input>,
+    buf     : VecDeque<char>,
+    offset  : usize,
+    line    : usize,
+    col     : usize,
+    
+    prev_type : Option<TokenType>,
+    prev_pos  : (usize, usize),
+    max_line  : usize,
+    line_head : usize,
+    indenting : bool,
+    errors    : Vec<LexerError>,
+    warnings  : Vec<LexerWarning>,
+    long_line : Option<usize>,
+    line_scan : (usize, usize, usize),
+    prev_end  : Option<usize>,
+    pending   : VecDeque<Token<'input>>,
+    held      : Option<Token<'input>>,
+    heredoc   : Option<(String, bool)>,
+    in_tag    : bool,
+    comments  : Vec<(Span, String)>,
+    trailing  : Vec<usize>,
+    ternaries : usize,
+    depth     : usize,
+    
+    annotation       : Annotation,
+    annotation_spans : Vec<Span>,
+    
+    decimal_sep         : char,
+    source_map          : Option<SourceMap<'input>>,
+    disallowed          : HashSet<TokenType>,
+    max_errors          : Option<usize>,
+    stop_on_error       : bool,
+    regex_literals      : bool,
+    atoms               : bool,
+    warn_trailing_comma : bool,
+    max_ident_len       : Option<(usize, LengthPolicy)>,
+    rules               : Vec<ScanRule<'input>>,
+    detect_bidi         : bool,
+    param_styles        : HashSet<ParamStyle>,
+    verbatim            : Option<(String, String)>,
+    annotations         : bool,
+    lone_cr             : CrPolicy,
+    percent_literals    : bool,
+    strict_leading_zero : bool,
+    max_line_chars      : Option<usize>,
+    no_space_markers    : bool,
+    line_comments       : Vec<String>,
+    line_keywords       : HashSet<String>,
+    word_mode           : bool,
+    html_tags           : bool,
+    collect_comments    : bool,
+    convention          : Option<Convention>,
+    continuation        : Option<char>,
+    symbol_chars        : Option<String>,
+    tab_width           : Option<usize>,
+    expand_tabs         : bool,
+    heredocs            : bool,
+    word_operators      : HashSet<String>,
+    forbid_tabs         : bool,
+    units               : HashSet<String>,
+    strict_units        : bool,
+    directives          : Vec<String>,
+    ternary_colons      : bool,
+    ident_quote         : Option<char>,
+    line_breaks         : Vec<char>,
+    max_depth           : Option<usize>,
+}
+
+impl<'input> Lexer<'input>
+{
+    /// Creates a new lexer to tokenize the given `text`.
+    ///
+    pub fn new(text: &'input str) -> Self
+    {
+        Lexer { 
+            status  : LexerStatus::Okay, 
+            text, 
+            ascii   : text.is_ascii(),
+            chars   : text.chars(),
+            buf     : VecDeque::new(),
+            offset  : 0,
+            line    : 0,
+            col     : 0,
+            
+            prev_type : None,
+            prev_pos  : (0, 0),
+            max_line  : 0,
+            line_head : 0,
+            indenting : true,
+            errors    : Vec::new(),
+            warnings  : Vec::new(),
+            long_line : None,
+            line_scan : (0, 0, 0),
+            prev_end  : None,
+            pending   : VecDeque::new(),
+            held      : None,
+            heredoc   : None,
+            in_tag    : false,
+            comments  : Vec::new(),
+            trailing  : Vec::new(),
+            ternaries : 0,
+            depth     : 0,
+            
+            annotation       : Annotation::Idle,
+            annotation_spans : Vec::new(),
+            
+            decimal_sep         : '.',
+            source_map          : None,
+            disallowed          : HashSet::new(),
+            max_errors          : None,
+            stop_on_error       : true,
+            regex_literals      : false,
+            atoms               : false,
+            warn_trailing_comma : false,
+            max_ident_len       : None,
+            rules               : Vec::new(),
+            detect_bidi         : false,
+            param_styles        : HashSet::new(),
+            verbatim            : None,
+            annotations         : false,
+            lone_cr             : CrPolicy::Preserve,
+            percent_literals    : false,
+            strict_leading_zero : false,
+            max_line_chars      : None,
+            no_space_markers    : false,
+            line_comments       : Vec::new(),
+            line_keywords       : HashSet::new(),
+            word_mode           : false,
+            html_tags           : false,
+            collect_comments    : false,
+            convention          : None,
+            continuation        : None,
+            symbol_chars        : None,
+            tab_width           : None,
+            expand_tabs         : false,
+            heredocs            : false,
+            word_operators      : HashSet::new(),
+            forbid_tabs         : false,
+            units               : HashSet::new(),
+            strict_units        : false,
+            directives          : Vec::new(),
+            ternary_colons      : false,
+            ident_quote         : None,
+            line_breaks         : Vec::new(),
+            max_depth           : None,
+        }
+    }
+    
+    /// Lexes `text` up to the first line that starts with `marker`, as for 
+    /// the end of a fenced code block in Markdown. Returns the tokens and the
+    /// number of bytes consumed, through the end of the marker; this is the
+    /// whole text if there's no marker. Lexing stops early on an error. See
+    /// `until_marker()` to lex with other options.
+    ///
+    pub fn scan_until_marker(text: &'input str, marker: &str) 
+        -> (Vec<Token<'input>>, usize)
+    {
+        Lexer::new(text).until_marker(marker)
+    }
+    
+    /// Like `scan_until_marker()`, but lexes the text with this lexer's 
+    /// options. Lines start after `\n` and after any of the 
+    /// `line_break_chars()`.
+    ///
+    pub fn until_marker(mut self, marker: &str) 
+        -> (Vec<Token<'input>>, usize)
+    {
+        let text  = self.text;
+        let start = |i: usize| text[i..].starts_with(marker);
+        let end   = if start(0) { Some(0) } else {
+            text.char_indices()
+                .find(|&(i, c)| self.is_line_break(c) 
+                                && start(i + c.len_utf8()))
+                .map(|(i, c)| i + c.len_utf8())
+        };
+        match end {
+            Some(end) => {
+                self.text  = &text[..end];
+                self.chars = self.text.chars();
+                (self.collect(), end + marker.len())
+            },
+            None => (self.collect(), text.len()),
+        }
+    }
+    
+    /// Lexes `text` into `buf`, which is cleared first, and returns the 
+    /// lexer's final status. Reusing the same `buf` for repeated calls reuses
+    /// its capacity rather than allocating anew.
+    ///
+    pub fn tokenize_into(text: &'input str, buf: &mut Vec<Token<'input>>) 
+        -> LexerStatus
+    {
+        let mut lexer = Lexer::new(text);
+        buf.clear();
+        buf.extend(&mut lexer);
+        lexer.status
+    }
+    
+    /// Creates a new lexer that scans a copy of `text` allocated in `arena`. 
+    /// The tokens borrow from the arena rather than from `text`.
+    ///
+    pub fn in_arena(text: &str, arena: &'input Arena) -> Self
+    {
+        Lexer::new(arena.alloc_str(text))
+    }
+    
+    /// Creates a new lexer for `text` that consults the user scanner `rules`,
+    /// in order, at the start of each token. The first rule whose predicate
+    /// accepts the character scans the token; if none do, the built in rules
+    /// apply. This allows custom token kinds, typically `TokenType::Custom`.
+    ///
+    pub fn with_rules(text: &'input str, rules: Vec<ScanRule<'input>>) -> Self
+    {
+        let mut lexer = Lexer::new(text);
+        lexer.rules = rules;
+        lexer
+    }
+    
+    /// Sets the character the numeric scanner treats as the decimal point. The
+    /// default is `'.'`. Passing `','` lets locales that write `3,14` lex it as
+    /// a single `FloatLiteral`; this is mutually exclusive with using `,` as
+    /// punctuation between numbers, so `1,2` is no longer `1`, `Comma`, `2`. 
+    /// Either way, there can be no whitespace on either side of the decimal 
+    /// point.
+    ///
+    pub fn decimal_separator(mut self, sep: char) -> Self
+    {
+        self.decimal_sep = sep;
+        self
+    }
+    
+    /// Returns the status of the lexer. This can be called after the lexer
+    /// stops producing tokens to find out if it parsed the full text or
+    /// encounted an error along the way.
+    ///
+    pub fn status(&self) -> &LexerStatus
+    {
+        &self.status
+    }
+    
+    /// Returns `true` if the text being scanned is entirely ASCII, in which 
+    /// case consumers can skip any Unicode-aware handling of the tokens.
+    ///
+    pub fn is_ascii(&self) -> bool
+    {
+        self.ascii
+    }
+    
+    /// If the lexer stopped on an `UnrecognizedStart` error, clears the error
+    /// and skips past the offending character so iteration can continue with
+    /// the text after it. Returns `true` if the lexer was able to resume.
+    ///
+    pub fn skip_unrecognized(&mut self) -> bool
+    {
+        if let LexerStatus::Error(LexerError::UnrecognizedStart { .. }) 
+            = self.status {
+            let end = self.consumed();
+            self.status = LexerStatus::Okay;
+            self.seek(end);
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Sets the maximum number of errors `next_recovering()` will report. 
+    /// Once that many have been recorded the lexer stops for good with a
+    /// status of `TooManyErrors`, which bounds the work done on garbage input.
+    ///
+    pub fn max_errors(mut self, n: usize) -> Self
+    {
+        self.max_errors = Some(n);
+        self
+    }
+    
+    /// Sets whether iterating over the lexer stops at the first error, which
+    /// is the default. With `false`, the iterator recovers like 
+    /// `next_recovering()`, so a plain `for` loop keeps producing the valid
+    /// tokens past errors; the errors are available from `.errors()`.
+    ///
+    pub fn stop_on_error(mut self, stop: bool) -> Self
+    {
+        self.stop_on_error = stop;
+        self
+    }
+    
+    /// Returns the errors recorded so far by `next_recovering()`.
+    ///
+    pub fn errors(&self) -> &[LexerError]
+    {
+        &self.errors
+    }
+    
+    /// Produces the next token like the iterator does, but recovers from 
+    /// errors rather than stopping. An error is returned as `Some(Err(<e>))`,
+    /// recorded, and the text involved is skipped so the next call resumes 
+    /// after it. `None` is returned at the end of the text, or once
+    /// `max_errors` have been recorded.
+    ///
+    pub fn next_recovering(&mut self) 
+        -> Option<Result<Token<'input>, LexerError>>
+    {
+        use LexerStatus::*;
+        if let Some(token) = self.next_token() {
+            return Some(Ok(token));
+        }
+        match std::mem::replace(&mut self.status, Okay) {
+            Error(e) => {
+                self.errors.push(e.clone());
+                if self.max_errors.is_some_and(|n| self.errors.len() >= n) {
+                    self.status = TooManyErrors;
+                } else {
+                    let end = self.consumed();
+                    self.seek(end);
+                }
+                Some(Err(e))
+            },
+            status => {
+                self.status = status;
+                None
+            },
+        }
+    }
+    
+    /// Returns the length in characters of the longest line scanned so far, 
+    /// not counting its line break. Called after the lexer finishes, this is
+    /// the longest line in the text.
+    ///
+    pub fn max_line_length(&self) -> usize
+    {
+        self.max_line.max(self.line_chars(self.offset))
+    }
+    
+    /// Has the lexer report token positions and spans in the coordinates of
+    /// the original source described by `map`, rather than in those of the
+    /// (preprocessed) text being scanned. Diagnostics then point at the text
+    /// the user actually wrote.
+    ///
+    pub fn source_map(mut self, map: SourceMap<'input>) -> Self
+    {
+        self.source_map = Some(map);
+        self
+    }
+    
+    /// Makes any token of type `type_` an error. Instead of producing the
+    /// token, the lexer stops with a `LexerError::Disallowed` at its position.
+    /// This can be called more than once to disallow several types, which is
+    /// useful for enforcing a subset grammar during lexing.
+    ///
+    pub fn error_on_type(mut self, type_: TokenType) -> Self
+    {
+        self.disallowed.insert(type_);
+        self
+    }
+    
+    /// Enables JavaScript-style `/pattern/flags` regex literals. A `/` is 
+    /// taken to start a `RegexLiteral` where an operand is expected, i.e. at
+    /// the start of input or after an operator, keyword, opening delimiter,
+    /// `,` or `;`; anywhere else it's the division operator.
+    ///
+    pub fn regex_literals(mut self, enable: bool) -> Self
+    {
+        self.regex_literals = enable;
+        self
+    }
+    
+    /// Enables atoms mode, where the fine-grained token types are ignored and
+    /// the text is simply split into whitespace-delimited `Atom` tokens, like 
+    /// shell words. Quotes are honored, so `"a b"` is a single atom.
+    ///
+    pub fn atoms(mut self, enable: bool) -> Self
+    {
+        self.atoms = enable;
+        self
+    }
+    
+    /// Has the lexer record a `TrailingComma` warning for each comma that 
+    /// comes right before a closing delimiter, as in `[1, 2, ]`. The comma
+    /// token is still produced.
+    ///
+    pub fn warn_trailing_comma(mut self, enable: bool) -> Self
+    {
+        self.warn_trailing_comma = enable;
+        self
+    }
+    
+    /// Caps identifiers at `max` characters. Longer identifiers are handled
+    /// according to `policy`: either an error, or truncation with a warning.
+    /// Keywords aren't affected.
+    ///
+    pub fn max_identifier_len(mut self, max: usize, policy: LengthPolicy) 
+        -> Self
+    {
+        self.max_ident_len = Some((max, policy));
+        self
+    }
+    
+    /// Has the lexer stop with a `LexerError::BidiControl` on any Unicode 
+    /// bidirectional control character (e.g. U+202E) in the text, guarding
+    /// against "Trojan Source" attacks. The characters are flagged wherever 
+    /// they appear literally, including strings; only escape sequences can
+    /// spell them safely.
+    ///
+    pub fn detect_bidi(mut self, enable: bool) -> Self
+    {
+        self.detect_bidi = enable;
+        self
+    }
+    
+    /// Turns on the strict profile: the checks that guard against text that
+    /// is misleading or malicious. Currently this is `detect_bidi(true)`.
+    ///
+    pub fn strict(self) -> Self
+    {
+        self.detect_bidi(true)
+    }
+    
+    /// Enables recognition of SQL parameter placeholders in the given style as
+    /// `Parameter` tokens. This can be called more than once to enable several
+    /// styles. The parameter's name is available from `.parameter_name()`.
+    ///
+    pub fn parameter_style(mut self, style: ParamStyle) -> Self
+    {
+        self.param_styles.insert(style);
+        self
+    }
+    
+    /// Sets markers for verbatim blocks. Everything between `open` and `close`
+    /// becomes a single `Verbatim` token, with no tokenization or escapes 
+    /// inside. The token's text is what's between the markers. Blocks don't
+    /// nest: the first `close` after `open` ends the block.
+    ///
+    pub fn verbatim_delimiters(mut self, open: &str, close: &str) -> Self
+    {
+        self.verbatim = Some((open.to_string(), close.to_string()));
+        self
+    }
+    
+    /// Enables annotations mode for Java/Kotlin-style `@Name(args)`. The `@`
+    /// becomes an `At` token, and the tokens of the annotation stay as normal,
+    /// but the byte range of each complete annotation, including any balanced
+    /// argument list, is recorded for `.annotation_spans()`. `@name` as an 
+    /// enabled SQL parameter style takes precedence.
+    ///
+    pub fn annotations(mut self, enable: bool) -> Self
+    {
+        self.annotations = enable;
+        self
+    }
+    
+    /// Returns the spans of the complete annotations scanned so far in 
+    /// annotations mode.
+    ///
+    pub fn annotation_spans(&self) -> &[Span]
+    {
+        &self.annotation_spans
+    }
+    
+    /// Sets the policy for a lone carriage return (a `\r` not followed by 
+    /// `\n`) inside a string literal: keep it, normalize it to `\n`, or make
+    /// it an error. `\r\n` line breaks aren't affected.
+    ///
+    pub fn lone_cr(mut self, policy: CrPolicy) -> Self
+    {
+        self.lone_cr = policy;
+        self
+    }
+    
+    /// Enables Ruby-style percent literals: `%w[a b c]` as a `WordListLiteral`,
+    /// `%i(sym list)` as a `SymbolListLiteral`, and `%q{text}` as a 
+    /// `StringLiteral`. The character after the type letter is the opening 
+    /// delimiter and determines the closing one.
+    ///
+    pub fn percent_literals(mut self, enable: bool) -> Self
+    {
+        self.percent_literals = enable;
+        self
+    }
+    
+    /// Forbids leading zeros on integers, as some languages do to avoid any
+    /// confusion with octal: `0123` and `00` become `LexerError::LeadingZero`.
+    /// A lone `0` and floats such as `0.5` are still fine.
+    ///
+    pub fn strict_leading_zero(mut self, enable: bool) -> Self
+    {
+        self.strict_leading_zero = enable;
+        self
+    }
+    
+    /// Limits lines to `limit` characters, not counting the line break. The
+    /// first character past the limit on a line gives a 
+    /// `LexerError::LineTooLong` there; each line is reported once.
+    ///
+    pub fn max_line_chars(mut self, limit: usize) -> Self
+    {
+        self.max_line_chars = Some(limit);
+        self
+    }
+    
+    /// Has the lexer emit a zero-width `NoSpace` token between any two 
+    /// tokens that touch, with no whitespace between them. `a+b` gives `a`,
+    /// `NoSpace`, `+`, `NoSpace`, `b`, while `a + b` gives no markers.
+    ///
+    pub fn no_space_markers(mut self, enable: bool) -> Self
+    {
+        self.no_space_markers = enable;
+        self
+    }
+    
+    /// Treats text from any of the `markers`, e.g. `//` or `#`, to the end of 
+    /// the line as a comment, which is skipped like whitespace.
+    ///
+    pub fn line_comments(mut self, markers: &[&str]) -> Self
+    {
+        self.line_comments = markers.iter().map(|m| m.to_string()).collect();
+        self
+    }
+    
+    /// Makes `words` soft keywords, as for the commands of a command-style 
+    /// language: each is a `Keyword` when it's the first token on its line,
+    /// and an `Identifier` anywhere else.
+    ///
+    pub fn line_start_keywords(mut self, words: &[&str]) -> Self
+    {
+        self.line_keywords = words.iter().map(|w| w.to_string()).collect();
+        self
+    }
+    
+    /// Enables word mode, for stack languages like Forth: everything up to the
+    /// next whitespace is a single word, lexed as an `Identifier` (or a 
+    /// `Keyword`), so `+` and `dup` are alike. Numbers and strings are still 
+    /// lexed as such.
+    ///
+    pub fn word_mode(mut self, enable: bool) -> Self
+    {
+        self.word_mode = enable;
+        self
+    }
+    
+    /// Enables HTML/XML tag mode, for templates. Tags lex as `TagOpen`, 
+    /// attributes, and then `TagEnd` or `TagSelfClose`; closing tags as 
+    /// `TagClose`. Inside a tag, attribute names are `AttrName` tokens, and
+    /// the rest, e.g. `=` and quoted values, lexes as usual. Everything 
+    /// between tags, whitespace included, is `Text`.
+    ///
+    pub fn html_tags(mut self, enable: bool) -> Self
+    {
+        self.html_tags = enable;
+        self
+    }
+    
+    /// Has the lexer keep the comments it skips, e.g. for documentation 
+    /// tools. They're available from `.comments()`.
+    ///
+    pub fn collect_comments(mut self, enable: bool) -> Self
+    {
+        self.collect_comments = enable;
+        self
+    }
+    
+    /// Returns the comments skipped so far, if `collect_comments()` is on, as
+    /// the span and text of each, including its marker.
+    ///
+    pub fn comments(&self) -> &[(Span, String)]
+    {
+        &self.comments
+    }
+    
+    /// Checks each identifier against the naming `convention`, recording an
+    /// `IdentifierCase` warning for those that don't follow it.
+    ///
+    pub fn identifier_convention(mut self, convention: Convention) -> Self
+    {
+        self.convention = Some(convention);
+        self
+    }
+    
+    /// Sets a line continuation character, e.g. `\`. Outside of strings, it
+    /// joins the line it ends to the next: it's skipped along with the line
+    /// break, which still counts for line numbers. Anywhere else, it lexes as
+    /// it would otherwise.
+    ///
+    pub fn line_continuation_char(mut self, ch: char) -> Self
+    {
+        self.continuation = Some(ch);
+        self
+    }
+    
+    /// Enables Lisp-style symbols: a word made up only of operator 
+    /// characters, `+-*/<>=!?%&|^~`, and standing alone between whitespace or
+    /// delimiters, is a single `Symbol`. So `(+ 1 2)` and `(<= a b)` have the
+    /// symbols `+` and `<=`, while `a+b` is still `a`, `+`, `b`. To have every
+    /// word lex as one token instead, see `word_mode()`.
+    ///
+    pub fn lisp_symbols(mut self, enable: bool) -> Self
+    {
+        self.symbol_chars = if enable { Some("+-*/<>=!?%&|^~".to_string()) }
+                            else      { None };
+        self
+    }
+    
+    /// Enables Lisp-style symbols, as `lisp_symbols()` does, but made up of 
+    /// the given operator characters.
+    ///
+    pub fn symbol_chars(mut self, chars: &str) -> Self
+    {
+        self.symbol_chars = Some(chars.to_string());
+        self
+    }
+    
+    /// Has tabs in the indentation at the start of a line advance the column
+    /// to the next multiple of `width`, rather than by one.
+    ///
+    pub fn tab_width(mut self, width: usize) -> Self
+    {
+        self.tab_width = Some(width.max(1));
+        self
+    }
+    
+    /// Has the lexer emit the indentation at the start of each line as a 
+    /// `Whitespace` token, with its tabs expanded to spaces. The tab width is
+    /// the one set with `tab_width()`, or 8.
+    ///
+    pub fn expand_tabs(mut self, enable: bool) -> Self
+    {
+        self.expand_tabs = enable;
+        self
+    }
+    
+    /// Enables shell-style heredocs. `<<TAG` is a `HeredocStart`; the rest of
+    /// its line lexes as usual, and the body runs from the next line up to a
+    /// line that is just `TAG`, the `HeredocEnd`. With an unquoted tag, `$name`
+    /// and `${...}` in the body are `Interpolation` tokens between `Heredoc`
+    /// text, and `\$` is a literal `$`. With a quoted tag, `<<'TAG'` or 
+    /// `<<"TAG"`, the body is a single verbatim `Heredoc`.
+    ///
+    pub fn heredocs(mut self, enable: bool) -> Self
+    {
+        self.heredocs = enable;
+        self
+    }
+    
+    /// Makes `words`, e.g. `and`, `or`, and `not`, operators: they lex as 
+    /// `Operator` tokens rather than as identifiers or keywords.
+    ///
+    pub fn word_operators(mut self, words: &[&str]) -> Self
+    {
+        self.word_operators = words.iter().map(|w| w.to_string()).collect();
+        self
+    }
+    
+    /// Forbids tab characters anywhere in the text, indentation and strings
+    /// included: each is a `LexerError::TabCharacter`.
+    ///
+    pub fn forbid_tabs(mut self, enable: bool) -> Self
+    {
+        self.forbid_tabs = enable;
+        self
+    }
+    
+    /// Allows numbers to have a unit suffix from `units`, e.g. `px`, `em`, or
+    /// `ms`, that's part of the literal: `10px` is a single `NumericLiteral`
+    /// and `2.5em` a `FloatLiteral`. The unit is available from 
+    /// `Token::unit()`. Other suffixes are left to lex as usual, unless 
+    /// `strict_units()` is on.
+    ///
+    pub fn units(mut self, units: &[&str]) -> Self
+    {
+        self.units = units.iter().map(|u| u.to_string()).collect();
+        self
+    }
+    
+    /// With `units()`, makes a suffix on a number that isn't one of the 
+    /// units a `LexerError::UnknownUnit`.
+    ///
+    pub fn strict_units(mut self, enable: bool) -> Self
+    {
+        self.strict_units = enable;
+        self
+    }
+    
+    /// Makes line comments that start with any of `prefixes`, e.g. `# noqa` 
+    /// or `// @ts-`, `Directive` tokens, while other comments are still 
+    /// skipped. The prefixes include the comment marker, which must be one set
+    /// with `line_comments()`.
+    ///
+    pub fn directives(mut self, prefixes: &[&str]) -> Self
+    {
+        self.directives = prefixes.iter().map(|p| p.to_string()).collect();
+        self
+    }
+    
+    /// Returns the lines scanned so far that end in whitespace, spaces or 
+    /// tabs before the line break, outside of any token.
+    ///
+    pub fn trailing_whitespace_lines(&self) -> &[usize]
+    {
+        &self.trailing
+    }
+    
+    /// Tells the colons of ternaries apart from others, e.g. those of type 
+    /// annotations: a `:` that closes a `?` is a `TernaryColon` rather than a
+    /// `Colon`. Ternaries can nest.
+    ///
+    pub fn ternary_colons(mut self, enable: bool) -> Self
+    {
+        self.ternary_colons = enable;
+        self
+    }
+    
+    /// Enables quoted identifiers, as in SQL, delimited by `quote`, e.g. 
+    /// `` ` ``. A doubled quote inside stands for the quote itself, so 
+    /// `` `a``b` `` is the `Identifier` `` a`b ``. The token text is the 
+    /// resolved name; the span covers the quotes.
+    ///
+    pub fn quoted_identifiers(mut self, quote: char) -> Self
+    {
+        self.ident_quote = Some(quote);
+        self
+    }
+    
+    /// Adds to the characters that break lines, e.g. `'\x1e'` for data with
+    /// record separators. Each counts as a line break for positions, as `\n`
+    /// always does.
+    ///
+    pub fn line_break_chars(mut self, chars: &[char]) -> Self
+    {
+        self.line_breaks = chars.to_vec();
+        self
+    }
+    
+    /// Limits how deeply `()`, `[]`, and `{}` can nest, to protect recursive
+    /// parsers downstream from pathological input. An opening delimiter more
+    /// than `limit` levels deep is a `LexerError::NestingTooDeep`.
+    ///
+    pub fn max_bracket_depth_limit(mut self, limit: usize) -> Self
+    {
+        self.max_depth = Some(limit);
+        self
+    }
+    
+    /// Returns the warnings recorded so far.
+    ///
+    pub fn warnings(&self) -> &[LexerWarning]
+    {
+        &self.warnings
+    }
+    
+    /// Writes each token's compact form to `w`, one per line, as it's 
+    /// scanned, and returns the lexer's final status. Large inputs can be 
+    /// logged this way without collecting the tokens into a `Vec` first.
+    ///
+    pub fn stream_to<W: io::Write>(mut self, w: &mut W) 
+        -> io::Result<LexerStatus>
+    {
+        while let Some(token) = self.next_token() {
+            writeln!(w, "{}", token)?;
+        }
+        Ok(self.status)
+    }
+    
+    /// Attaches a payload to each token, computed by `f` as the token is 
+    /// scanned, e.g. a category or a hash. The tokens come from the returned
+    /// iterator.
+    ///
+    pub fn with_token_data<T, F>(self, f: F) -> WithTokenData<'input, F>
+    where
+        F: FnMut(&Token<'input>) -> T,
+    {
+        WithTokenData { lexer: self, f }
+    }
+    
+    /// Batches the tokens into vectors of `n` each, the last possibly fewer,
+    /// e.g. to hand off to the stages of a pipeline.
+    ///
+    pub fn chunks(mut self, n: usize) 
+        -> impl Iterator<Item = Vec<Token<'input>>> + 'input
+    {
+        let n = n.max(1);
+        std::iter::from_fn(move || {
+            let chunk: Vec<_> = self.by_ref().take(n).collect();
+            if chunk.is_empty() { None } else { Some(chunk) }
+        })
+    }
+    
+    /// Boxes the lexer as a `TokenSource` trait object for dynamic dispatch.
+    ///
+    pub fn into_token_source(self) -> Box<dyn TokenSource<'input> + 'input>
+    {
+        Box::new(self)
+    }
+    
+    /// Returns the next byte of the text, without consuming it, or `None` at
+    /// the end. Characters that were looked ahead at or put back count as not
+    /// consumed yet.
+    ///
+    pub fn peek_byte(&self) -> Option<u8>
+    {
+        self.text.as_bytes().get(self.consumed()).copied()
+    }
+    
+    /// Returns the offset of the first character not yet consumed from the 
+    /// text, i.e. just past the characters taken so far by `next_char()`.
+    ///
+    fn consumed(&self) -> usize
+    {
+        let buffered: usize = self.buf.iter().map(|ch| ch.len_utf8()).sum();
+        self.text.len() - self.chars.as_str().len() - buffered
+    }
+    
+    /// Moves the lexer forward to `offset`, discarding any buffered look 
+    /// ahead and updating the line and column for the text skipped over.
+    ///
+    fn seek(&mut self, offset: usize)
+    {
+        self.advance(offset - self.offset);
+        self.chars = self.text[offset..].chars();
+        self.buf.clear();
+    }
+    
+    /// Moves the offset forward `len` bytes past the text of a token (or text 
+    /// being skipped), updating the line and column for any line breaks in it.
+    ///
+    fn advance(&mut self, len: usize)
+    {
+        let start = self.offset;
+        let end   = start + len;
+        let text  = &self.text[start..end];
+        for (i, ch) in text.char_indices() {
+            let lone_cr = ch == '\r' && !text[i + 1..].starts_with('\n');
+            if self.is_line_break(ch)
+               || (lone_cr && self.lone_cr == CrPolicy::Normalize) {
+                self.max_line  = self.max_line.max(self.line_chars(start + i));
+                self.line_head = start + i + ch.len_utf8();
+                self.indenting = true;
+                self.line     += 1;
+                self.col       = 0;
+            } else {
+                self.indenting = self.indenting && ch.is_whitespace();
+                self.col      += 1;
+            }
+        }
+        self.offset = end;
+    }
+    
+    /// Produces the next character to process as `Some(<ch>)`, or `None` if 
+    /// finished.
+    ///
+    fn next_char(&mut self) -> Option<char>
+    {
+        use LexerStatus::*;
+        match self.status {
+            Okay => {
+                if !self.buf.is_empty() {
+                    self.buf.pop_front()
+                } else {
+                    let next = self.chars.next();
+                    if next.is_none() {
+                        self.status = EndOfStream;
+                    }
+                    next
+                }
+            },
+            _ => { None }
+        }
+    }
+    
+    /// Look ahead `ahead` number of characters.
+    ///
+    fn look_ahead(&mut self, ahead: usize) -> Option<char>
+    {
+        let mut end = false;
+        let     len = self.buf.len();
+        
+        for _ in len..ahead {
+            match self.chars.next() {
+                Some(ch) => { self.buf.push_back(ch); },
+                None     => { end = true; break; },
+            }       
+        }
+        if !end && ahead > 0 {
+            Some(self.buf[ahead - 1])
+        } else {
+            None
+        }
+    }
+    
+    /// Put a single character back in the text stream at the front. The 
+    /// `next_char()` method will return this character on its next invocation.
+    ///
+    fn put_back(&mut self, ch: char) 
+    {
+        self.buf.push_front(ch);
+    }
+    
+    /// Creates a token of the given type for `text`, which starts at the 
+    /// current position. Positions are translated through the source map if
+    /// there is one.
+    ///
+    fn token(&self, type_: TokenType, text: &'input str) -> Token<'input>
+    {
+        let span = Span::new(self.offset, self.offset + text.len());
+        
+        match &self.source_map {
+            Some(map) => {
+                let start = map.to_original(span.start);
+                let end   = if span.is_empty() { start } 
+                            else { map.to_original(span.end - 1) + 1 };
+                let (line, col) = map.original_pos(start);
+                Token::new(type_, text, line, col, Span::new(start, end))
+            },
+            None => Token::new(type_, text, self.line, self.col, span),
+        }
+    }
+    
+    /// Produces the next token for the lexer as `Some(<token>)`. `None` is 
+    /// returned if the lexer reached the end of the input text or an error
+    /// occurred, which can be checked using `.status()`.
+    ///
+    fn next_token(&mut self) -> Option<Token<'input>>
+    {
+        use TokenType::*;
+        use LexerError::*;
+        use LexerStatus::*;
+        let start = self.offset;
+        
+        if let Some(token) = self.held.take() {
+            return Some(token);
+        }
+        let mut ret = match self.pending.pop_front() {
+            // Queued up tokens were scanned along with an earlier one, but
+            // still go through the checks below.
+            Some(token) => Some(token),
+            None if !matches!(self.status, Okay) => return None,
+            None => self.scan_token(),
+        };
+        let consumed = self.consumed();
+        let from     = start.max(self.line_head).min(consumed);
+        if !self.text[from..consumed].chars().all(char::is_whitespace) {
+            self.indenting = false;
+        }
+        if self.detect_bidi {
+            let end  = self.consumed();
+            let bidi = self.text[start..end]
+                           .char_indices()
+                           .find(|&(_, ch)| is_bidi_control(ch));
+            
+            if let Some((i, codepoint)) = bidi {
+                let (line, col) = self.position_of(start + i);
+                let span = Span::new(start + i, 
+                                     start + i + codepoint.len_utf8());
+                self.status = Error(BidiControl { line, col, codepoint, span });
+                ret = None;
+            }
+        }
+        if self.forbid_tabs && !matches!(self.status, Error(_)) {
+            let end = self.consumed();
+            if let Some(i) = self.text[start..end].find('\t') {
+                let (line, col) = self.position_of(start + i);
+                let span = Span::new(start + i, start + i + 1);
+                self.status = Error(TabCharacter { line, col, span });
+                ret = None;
+            }
+        }
+        if let Some(limit) = self.max_line_chars {
+            if !matches!(self.status, Error(_)) {
+                if let Some(e) = self.line_too_long(limit) {
+                    self.status = Error(e);
+                    ret = None;
+                }
+            }
+        }
+        if let Some(token) = &mut ret {
+            if self.lone_cr != CrPolicy::Preserve 
+               && matches!(token.type_, StringLiteral | ByteStringLiteral) {
+                let lone = token.text.char_indices().find(|&(i, ch)| {
+                    ch == '\r' && !token.text[i + 1..].starts_with('\n')
+                });
+                if let Some((i, _)) = lone {
+                    if self.lone_cr == CrPolicy::Error {
+                        let (line, col) = self.position_of(token.span.start 
+                                                           + i);
+                        self.status = Error(
+                            LoneCarriageReturn { 
+                                message: "Lone carriage return in string."
+                                         .to_string(),
+                                line,
+                                col,
+                                span: Span::new(token.span.start + i, 
+                                                token.span.start + i + 1),
+                         });
+                         ret = None;
+                    } else {
+                        token.text = Cow::Owned(lone_cr_to_lf(&token.text));
+                    }
+                }
+            }
+        }
+        if let Some(token) = &ret {
+            if token.type_.is_open_delimiter() {
+                self.depth += 1;
+                let depth = self.depth;
+                if let Some(limit) = self.max_depth.filter(|&l| depth > l) {
+                    self.status = Error(
+                        NestingTooDeep {
+                            line : token.line,
+                            col  : token.col,
+                            limit,
+                            span : token.span,
+                     });
+                     self.depth -= 1;
+                     ret = None;
+                }
+            } else if token.type_.is_close_delimiter() {
+                self.depth = self.depth.saturating_sub(1);
+            }
+        }
+        if let Some(token) = &ret {
+            if self.disallowed.contains(&token.type_) {
+                self.status = Error(
+                    Disallowed { 
+                        type_: token.type_,
+                        line : token.line,
+                        col  : token.col,
+                        span : token.span,
+                 });
+                 ret = None;
+            }
+        }
+        if let (Some(token), Some(convention)) = (&ret, self.convention) {
+            if token.type_ == Identifier && !convention.matches(&token.text) {
+                self.warnings.push(
+                    LexerWarning::IdentifierCase { 
+                        line: token.line, 
+                        col : token.col, 
+                        convention,
+                 });
+            }
+        }
+        if let Some(token) = &ret {
+            if self.warn_trailing_comma 
+               && token.type_.is_close_delimiter()
+               && self.prev_type == Some(Comma) {
+                let (line, col) = self.prev_pos;
+                self.warnings.push(LexerWarning::TrailingComma { line, col });
+            }
+            if token.type_ != Whitespace {
+                self.prev_type = Some(token.type_);
+                self.prev_pos  = (token.line, token.col);
+            }
+        }
+        if self.annotations {
+            self.track_annotation(ret.as_ref().map(|t| (t.type_, t.span)));
+        }
+        if self.no_space_markers {
+            if let Some(token) = ret.take() {
+                let span = token.span;
+                if self.prev_end == Some(span.start) {
+                    ret = Some(Token::new(NoSpace, "", token.line, token.col,
+                                          Span::new(span.start, span.start)));
+                    self.held = Some(token);
+                } else {
+                    ret = Some(token);
+                }
+                self.prev_end = Some(span.end);
+            }
+        }
+        if matches!(self.status, Error(_)) {
+            // Anything still queued up lies in the text the error skips.
+            self.pending.clear();
+        }
+        ret
+    }
+    
+    /// Scans the token at the current position for `next_token()`, which 
+    /// checks it.
+    ///
+    fn scan_token(&mut self) -> Option<Token<'input>>
+    {
+        use TokenType::*;
+        use LexerError::*;
+        use LexerStatus::*;
+        let mut ret = None;
+        
+        'outer: while let Some(ch) = self.next_char() {
+            if let Some(scan) = self.rule_for(ch) {
+                // User rule. The scanner starts over from the first character
+                // so it accounts for everything the rule consumes.
+                
+                self.put_back(ch);
+                let mut scanner = Scanner { lexer: self, len: 0 };
+                scanner.next_char();
+                let token = scan(&mut scanner);
+                let len   = scanner.len;
+                ret = Some(token);
+                self.advance(len);
+                break 'outer;
+            }
+            let symbol = self.symbol_len();
+            match ch {
+                '<' if self.html_tags && !self.in_tag 
+                       && starts_tag(&self.text[self.offset..]) => {
+                    // TagOpen or TagClose. A closing tag is taken whole.
+                    
+                    let off  = self.offset;
+                    let rest = &self.text[off..];
+                    if rest.starts_with("</") {
+                        match rest.find('>') {
+                            Some(i) => {
+                                ret = Some(self.token(TagClose, &rest[..=i]));
+                                self.seek(off + i + 1);
+                            },
+                            None => {
+                                self.status = Error(
+                                    Unterminated { 
+                                        message: "Unterminated closing tag."
+                                                 .to_string(),
+                                        line: self.line,
+                                        col : self.col,
+                                        span: Span::new(off, self.text.len()),
+                                 });
+                                 self.seek(self.text.len());
+                            },
+                        }
+                    } else {
+                        let len = 1 + tag_name_len(&rest[1..]);
+                        ret = Some(self.token(TagOpen, &rest[..len]));
+                        self.seek(off + len);
+                        self.in_tag = true;
+                    }
+                    break 'outer;
+                },
+                _ if self.html_tags && !self.in_tag => {
+                    // Text, up to the next tag.
+                    
+                    let off  = self.offset;
+                    let rest = &self.text[off..];
+                    let len  = rest.char_indices().skip(1)
+                                   .find(|&(i, _)| starts_tag(&rest[i..]))
+                                   .map_or(rest.len(), |(i, _)| i);
+                    ret = Some(self.token(Text, &rest[..len]));
+                    self.seek(off + len);
+                    break 'outer;
+                },
+                '>' if self.in_tag => {
+                    let off = self.offset;
+                    ret = Some(self.token(TagEnd, &self.text[off..off + 1]));
+                    self.offset += 1;
+                    self.col    += 1;
+                    self.in_tag  = false;
+                    break 'outer;
+                },
+                '/' if self.in_tag && self.look_ahead(1) == Some('>') => {
+                    self.next_char();
+                    let off = self.offset;
+                    ret = Some(self.token(TagSelfClose, 
+                                          &self.text[off..off + 2]));
+                    self.offset += 2;
+                    self.col    += 2;
+                    self.in_tag  = false;
+                    break 'outer;
+                },
+                _ if self.in_tag && (ch.is_alphabetic() || ch == '_') => {
+                    // AttrName.
+                    
+                    let off  = self.offset;
+                    let rest = &self.text[off..];
+                    let len  = tag_name_len(rest);
+                    ret = Some(self.token(AttrName, &rest[..len]));
+                    self.seek(off + len);
+                    break 'outer;
+                },
+                _ if self.heredoc.is_some() && self.is_line_break(ch) => {
+                    // Heredoc body, queued up to follow the rest of the line
+                    // the heredoc started on.
+                    
+                    self.seek(self.offset + ch.len_utf8());
+                    let (tag, quoted) = self.heredoc.take().unwrap_or_default();
+                    self.heredoc_body(&tag, quoted);
+                    ret = self.pending.pop_front();
+                    break 'outer;
+                },
+                _ if self.is_line_break(ch) => {
+                    let before = &self.text[..self.offset];
+                    let before = before.strip_suffix('\r').unwrap_or(before);
+                    if before.ends_with([' ', '\t']) {
+                        self.trailing.push(self.line);
+                    }
+                    self.max_line     = self.max_line
+                                            .max(self.line_chars(self.offset));
+                    self.offset      += ch.len_utf8();
+                    self.line_head    = self.offset;
+                    self.indenting    = true;
+                    self.line        += 1;
+                    self.col          = 0;
+                },
+                ' ' | '\t' if self.expand_tabs && self.indenting => {
+                    // Whitespace, the indentation with its tabs expanded.
+                    
+                    let off   = self.offset;
+                    let rest  = &self.text[off..];
+                    let len   = rest.find(|c| c != ' ' && c != '\t')
+                                    .unwrap_or(rest.len());
+                    let width = self.tab_width.unwrap_or(8);
+                    let mut spaces = String::new();
+                    for c in rest[..len].chars() {
+                        let stop = if c == '\t' { width - spaces.len() % width }
+                                   else         { 1 };
+                        spaces.extend(std::iter::repeat_n(' ', stop));
+                    }
+                    let mut token = self.token(Whitespace, &rest[..len]);
+                    token.text = Cow::Owned(spaces);
+                    ret = Some(token);
+                    let col = rest[..len].chars()
+                                         .fold(self.col, |col, c| {
+                                             if c == '\t' { 
+                                                 (col / width + 1) * width 
+                                             } else { 
+                                                 col + 1 
+                                             }
+                                         });
+                    self.seek(off + len);
+                    self.col = col;
+                    break 'outer;
+                },
+                '\t' if self.tab_width.is_some() && self.indenting => {
+                    self.offset += 1;
+                    self.col     = self.tab_stop(self.col, ch);
+                },
+                ' ' | '\t'| '\r' => {
+                    self.offset += 1;
+                    self.col    += 1;
+                },
+                _ if self.continuation == Some(ch) 
+                     && match self.look_ahead(1) {
+                            Some('\r') => self.look_ahead(2) == Some('\n'),
+                            Some(c)    => self.is_line_break(c),
+                            None       => false,
+                        } => {
+                    // Line continuation. The line break is skipped along 
+                    // with it, and the next line goes on the same logical 
+                    // line, so it isn't indentation or a line start.
+                    
+                    let off  = self.offset + ch.len_utf8();
+                    let rest = &self.text[off..];
+                    let len  = if rest.starts_with("\r\n") { 2 } 
+                               else { rest.chars().next()
+                                          .map_or(0, char::len_utf8) };
+                    self.seek(off + len);
+                    self.indenting = false;
+                },
+                _ if self.ident_quote == Some(ch) => {
+                    // Quoted Identifier. A doubled quote is an escaped one.
+                    
+                    let off  = self.offset;
+                    let rest = &self.text[off + ch.len_utf8()..];
+                    let mut name  = String::new();
+                    let mut chars = rest.char_indices().peekable();
+                    let mut end   = None;
+                    while let Some((i, c)) = chars.next() {
+                        if c != ch {
+                            name.push(c);
+                        } else if chars.next_if(|&(_, c)| c == ch).is_some() {
+                            name.push(ch);
+                        } else {
+                            end = Some(i + 2 * ch.len_utf8());
+                            break;
+                        }
+                    }
+                    match end {
+                        Some(len) => {
+                            let text      = &self.text[off..off + len];
+                            let mut token = self.token(Identifier, text);
+                            token.text = Cow::Owned(name);
+                            ret = Some(token);
+                            self.seek(off + len);
+                        },
+                        None => {
+                            self.status = Error(
+                                Unterminated { 
+                                    message: format!("Unterminated quoted \
+                                                     identifier, missing \
+                                                     '{}'.", ch),
+                                    line: self.line,
+                                    col : self.col,
+                                    span: Span::new(off, self.text.len()),
+                             });
+                             self.seek(self.text.len());
+                        },
+                    }
+                    break 'outer;
+                },
+                _ if self.comment_ahead() => {
+                    // Line comment. Skipped up to, but not including, the 
+                    // line break, unless it's a Directive.
+                    
+                    let off  = self.offset;
+                    let rest = &self.text[off..];
+                    let len  = rest.find(|c| self.is_line_break(c))
+                                   .unwrap_or(rest.len());
+                    let text = &rest[..len];
+                    if self.directives.iter()
+                                      .any(|p| text.starts_with(p.as_str())) {
+                        ret = Some(self.token(Directive, text));
+                        self.seek(off + len);
+                        break 'outer;
+                    }
+                    if self.collect_comments {
+                        self.comments.push((Span::new(off, off + len), 
+                                            text.to_string()));
+                    }
+                    self.seek(off + len);
+                },
+                _ if self.verbatim_ahead() => {
+                    // Verbatim. The token is the text between the markers, 
+                    // taken as is; the first closing marker ends it.
+                    
+                    let (open, close) = self.verbatim.clone()
+                                            .unwrap_or_default();
+                    let start = self.offset + open.len();
+                    match self.text[start..].find(&close) {
+                        Some(len) => {
+                            self.seek(start);
+                            let text = &self.text[start..start + len];
+                            ret = Some(self.token(Verbatim, text));
+                            self.seek(start + len + close.len());
+                        },
+                        None => {
+                            self.status = Error(
+                                Unterminated { 
+                                    message: format!("Unterminated verbatim \
+                                                     block, missing '{}'.", 
+                                                     close),
+                                    line: self.line,
+                                    col : self.col,
+                                    span: Span::new(self.offset, 
+                                                    self.text.len()),
+                             });
+                             self.seek(self.text.len());
+                        },
+                    }
+                    break 'outer;
+                },
+                _ if self.atoms && !ch.is_whitespace() => {
+                    // Atom. Runs to the next whitespace outside of quotes.
+                    
+                    let mut end = ch.len_utf8();
+                    if ch == '"' {
+                        match self.scan_string(end) {
+                            Ok(e)  => { end = e; },
+                            Err(e) => { self.status = Error(e); break 'outer; },
+                        }
+                    }
+                    while let Some(la) = self.look_ahead(1) {
+                        if la.is_whitespace() {
+                            break;
+                        }
+                        self.next_char();
+                        end += la.len_utf8();
+                        if la == '"' {
+                            match self.scan_string(end) {
+                                Ok(e)  => { end = e; },
+                                Err(e) => { 
+                                    self.status = Error(e); 
+                                    break 'outer; 
+                                },
+                            }
+                        }
+                    }
+                    let off  = self.offset;
+                    let text = &self.text[off..off + end];
+                    ret = Some(self.token(Atom, text));
+                    self.advance(end);
+                    break 'outer;
+                },
+                _ if symbol > 0 => {
+                    // Symbol.
+                    
+                    let off = self.offset;
+                    ret = Some(self.token(Symbol, 
+                                          &self.text[off..off + symbol]));
+                    self.seek(off + symbol);
+                    break 'outer;
+                },
+                _ if self.word_mode && ch.is_whitespace() => {
+                    // Other whitespace, e.g. `\u{a0}`, which also ends words.
+                    
+                    self.offset += ch.len_utf8();
+                    self.col    += 1;
+                },
+                _ if self.word_mode && ch != '"' && !self.number_ahead() => {
+                    // Word. Runs to the next whitespace, so operators and
+                    // words like `2dup` are all identifiers.
+                    
+                    let off  = self.offset;
+                    let rest = &self.text[off..];
+                    let len  = rest.find(char::is_whitespace)
+                                   .unwrap_or(rest.len());
+                    let text = &rest[..len];
+                    let type_ = if KEYWORDS.contains(text) { Keyword    } 
+                                else                       { Identifier };
+                    ret = Some(self.token(type_, text));
+                    self.seek(off + len);
+                    break 'outer;
+                },
+                '(' | ')' | '{' | '}' | '[' | ']' => {
+                    // Delimiter.
+                    
+                    let type_ = match ch {
+                        '(' => LParen,
+                        ')' => RParen,
+                        '{' => LBrace,
+                        '}' => RBrace,
+                        '[' => LBracket,
+                        _   => RBracket,
+                    };
+                    let off = self.offset;
+                    ret = Some(self.token(type_, &self.text[off..off + 1]));
+                    self.offset += 1;
+                    self.col    += 1;
+                    break 'outer;
+                },
+                '/' if self.regex_literals && self.regex_allowed() => {
+                    // RegexLiteral. Scans up to the closing unescaped `/`, 
+                    // which doesn't count inside a `[...]` class, then any
+                    // trailing flag letters.
+                    
+                    let mut escaped  = false;
+                    let mut in_class = false;
+                    let mut closed   = false;
+                    let mut end      = 1;
+                    
+                    while let Some(ch) = self.next_char() {
+                        if self.is_line_break(ch) {
+                            self.put_back(ch);
+                            break;
+                        }
+                        end += ch.len_utf8();
+                        match ch {
+                            _ if escaped     => { escaped  = false; },
+                            '\\'             => { escaped  = true;  },
+                            '['              => { in_class = true;  },
+                            ']'              => { in_class = false; },
+                            '/' if !in_class => { closed   = true; break; },
+                            _                => {},
+                        }
+                    }
+                    if !closed {
+                        self.status = Error(
+                            Unterminated { 
+                                message: "Unterminated regex literal."
+                                         .to_string(),
+                                line: self.line,
+                                col : self.col,
+                                span: Span::new(self.offset, self.consumed()),
+                         });
+                         break 'outer;
+                    }
+                    while let Some(la) = self.look_ahead(1) {
+                        if !la.is_ascii_alphabetic() {
+                            break;
+                        }
+                        self.next_char();
+                        end += 1;
+                    }
+                    let off  = self.offset;
+                    let text = &self.text[off..off + end];
+                    ret = Some(self.token(RegexLiteral, text));
+                    self.offset += end;
+                    self.col    += text.chars().count();
+                    break 'outer;
+                },
+                '<' if self.heredocs 
+                       && heredoc_tag(&self.text[self.offset..]).is_some() => {
+                    // HeredocStart. Checks up front that the body ends.
+                    
+                    let off  = self.offset;
+                    let rest = &self.text[off..];
+                    let (len, tag, quoted) = heredoc_tag(rest)
+                                                 .unwrap_or_default();
+                    let ends = rest.char_indices()
+                                   .find(|&(_, c)| self.is_line_break(c))
+                                   .is_some_and(|(i, c)| {
+                                       let body = &rest[i + c.len_utf8()..];
+                                       heredoc_end(body, &tag, |c| {
+                                           self.is_line_break(c)
+                                       }).is_some()
+                                   });
+                    if !ends {
+                        self.status = Error(
+                            Unterminated { 
+                                message: format!("Unterminated heredoc, \
+                                                 missing '{}'.", tag),
+                                line: self.line,
+                                col : self.col,
+                                span: Span::new(off, self.text.len()),
+                         });
+                         self.seek(self.text.len());
+                         break 'outer;
+                    }
+                    ret = Some(self.token(HeredocStart, &rest[..len]));
+                    self.seek(off + len);
+                    self.heredoc = Some((tag, quoted));
+                    break 'outer;
+                },
+                '+' | '-' | '*' | '/' | '=' => {
+                    // Operator.
+                    
+                    let off = self.offset;
+                    ret = Some(self.token(Operator, &self.text[off..off + 1]));
+                    self.offset += 1;
+                    self.col    += 1;
+                    break 'outer;
+                },
+                ';' => {
+                    ret = Some(self.token(Semicolon, ";"));
+                    self.offset += 1;
+                    self.col    += 1;
+                    break 'outer;
+                },
+                '"' => {
+                    // StringLiteral.
+                    
+                    match self.scan_string(1) {
+                        Ok(end) => {
+                            let off  = self.offset;
+                            let text = &self.text[off..off + end];
+                            ret = Some(self.token(StringLiteral, text));
+                            self.advance(end);
+                        },
+                        Err(e) => { self.status = Error(e); },
+                    }
+                    break 'outer;
+                },
+                'b' if self.look_ahead(1) == Some('"') => {
+                    // ByteStringLiteral.
+                    
+                    self.next_char();
+                    match self.scan_byte_string(2) {
+                        Ok(end) => {
+                            let off  = self.offset;
+                            let text = &self.text[off..off + end];
+                            ret = Some(self.token(ByteStringLiteral, text));
+                            self.advance(end);
+                        },
+                        Err(e) => { self.status = Error(e); },
+                    }
+                    break 'outer;
+                },
+                '@' if self.annotations && !self.parameter_ahead(ch) => {
+                    let off = self.offset;
+                    ret = Some(self.token(At, &self.text[off..off + 1]));
+                    self.offset += 1;
+                    self.col    += 1;
+                    break 'outer;
+                },
+                '%' if self.percent_literals 
+                       && matches!(self.look_ahead(1), Some('w' | 'q' | 'i'))
+                       && self.look_ahead(2).is_some_and(|c| {
+                              !c.is_alphanumeric() && !c.is_whitespace()
+                          }) => {
+                    // Percent literal, `%w[...]`, `%q{...}`, or `%i(...)`. A
+                    // bracket delimiter closes with its partner and may nest;
+                    // any other delimiter closes with itself.
+                    
+                    let kind  = self.next_char().unwrap_or('q');
+                    let open  = self.next_char().unwrap_or('(');
+                    let close = match open {
+                        '(' => ')',
+                        '[' => ']',
+                        '{' => '}',
+                        '<' => '>',
+                        _   => open,
+                    };
+                    let mut end   = 2 + open.len_utf8();
+                    let mut depth = 1;
+                    while let Some(ch) = self.next_char() {
+                        end += ch.len_utf8();
+                        if ch == close {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        } else if ch == open {
+                            depth += 1;
+                        }
+                    }
+                    if depth > 0 {
+                        self.status = Error(
+                            Unterminated { 
+                                message: format!("Unterminated %{} literal, \
+                                                 missing '{}'.", kind, close),
+                                line: self.line,
+                                col : self.col,
+                                span: Span::new(self.offset, self.consumed()),
+                         });
+                         break 'outer;
+                    }
+                    let type_ = match kind {
+                        'w' => WordListLiteral,
+                        'i' => SymbolListLiteral,
+                        _   => StringLiteral,
+                    };
+                    let off  = self.offset;
+                    let text = &self.text[off..off + end];
+                    ret = Some(self.token(type_, text));
+                    self.advance(end);
+                    break 'outer;
+                },
+                '?' | '$' | ':' | '@' if self.parameter_ahead(ch) => {
+                    // Parameter placeholder, `?`, `$1`, `:name`, or `@name`.
+                    
+                    let mut end = 1;
+                    while let Some(la) = self.look_ahead(1) {
+                        if ch == '?' || !(la.is_ascii_alphanumeric() 
+                                          || la == '_') {
+                            break;
+                        }
+                        self.next_char();
+                        end += 1;
+                    }
+                    let off  = self.offset;
+                    let text = &self.text[off..off + end];
+                    ret = Some(self.token(Parameter, text));
+                    self.offset += end;
+                    self.col    += end;
+                    break 'outer;
+                },
+                '?' => {
+                    // Operator `??`, QuestionDot `?.`, QuestionDotBracket 
+                    // `?.[`, or a bare Question, longest first. A digit 
+                    // after `?.` makes the `.` part of a number, as in 
+                    // `a?.5:1`.
+                    
+                    let (type_, end) = match (self.look_ahead(1), 
+                                              self.look_ahead(2)) {
+                        (Some('?'), _        ) => (Operator,           2),
+                        (Some('.'), Some('[')) => (QuestionDotBracket, 3),
+                        (Some('.'), Some(c)  ) 
+                            if c.is_ascii_digit() => (Question,        1),
+                        (Some('.'), _        ) => (QuestionDot,        2),
+                        _                      => (Question,           1),
+                    };
+                    for _ in 1..end {
+                        self.next_char();
+                    }
+                    if type_ == Question && self.ternary_colons {
+                        self.ternaries += 1;
+                    }
+                    let off = self.offset;
+                    ret = Some(self.token(type_, &self.text[off..off + end]));
+                    self.offset += end;
+                    self.col    += end;
+                    break 'outer;
+                },
+                ':' => {
+                    let type_ = if self.ternaries > 0 { TernaryColon } 
+                                else                  { Colon        };
+                    self.ternaries = self.ternaries.saturating_sub(1);
+                    let off = self.offset;
+                    ret = Some(self.token(type_, &self.text[off..off + 1]));
+                    self.offset += 1;
+                    self.col    += 1;
+                    break 'outer;
+                },
+                'r' if self.raw_hashes().is_some() => {
+                    // Raw StringLiteral, `r"..."` or `r#"..."#`. There are no
+                    // escapes, and line breaks are taken literally.
+                    
+                    let hashes = self.raw_hashes().unwrap_or(0);
+                    let mut end = 2 + hashes;
+                    for _ in 0..=hashes {
+                        self.next_char();
+                    }
+                    let mut closed = false;
+                    while let Some(ch) = self.next_char() {
+                        end += ch.len_utf8();
+                        if ch == '"' 
+                           && (1..=hashes).all(|i| self.look_ahead(i) 
+                                                   == Some('#')) {
+                            for _ in 0..hashes {
+                                self.next_char();
+                            }
+                            end   += hashes;
+                            closed = true;
+                            break;
+                        }
+                    }
+                    if !closed {
+                        self.status = Error(
+                            Unterminated { 
+                                message: "Unterminated raw string literal."
+                                         .to_string(),
+                                line: self.line,
+                                col : self.col,
+                                span: Span::new(self.offset, self.consumed()),
+                         });
+                         break 'outer;
+                    }
+                    let off  = self.offset;
+                    let text = &self.text[off..off + end];
+                    ret = Some(self.token(StringLiteral, text));
+                    self.advance(end);
+                    break 'outer;
+                },
+                'a'..='z' | 'A'..='Z' | '_' => {
+                    // Identifier or Keyword.
+                    
+                    let mut end = 1;
+                    while let Some(la) = self.look_ahead(1) {
+                        match la {
+                            'a'..='z' | 'A'..='Z' | '_' => {
+                                self.next_char();
+                                end += 1;
+                            },
+                            _ => break,
+                        }
+                    }
+                    let off      = self.offset;
+                    let text     = &self.text[off..off + end];
+                    let is_kw    = KEYWORDS.contains(text)
+                                   || (self.line_keywords.contains(text)
+                                       && self.indenting);
+                    let is_op    = self.word_operators.contains(text);
+                    let token    = if is_op      { Operator   } 
+                                   else if is_kw { Keyword    } 
+                                   else          { Identifier };
+                    let mut truncated = None;
+                    
+                    if let Some((max, policy)) = self.max_ident_len {
+                    if token == Identifier && end > max {
+                        match policy {
+                            LengthPolicy::Error => {
+                                self.status = Error(
+                                    IdentifierTooLong { 
+                                        message: format!(
+                                            "Identifier longer than {} \
+                                            characters, '{}'.", max, text),
+                                        line: self.line,
+                                        col : self.col,
+                                        span: Span::new(off, off + end),
+                                 });
+                                 break 'outer;
+                            },
+                            LengthPolicy::Truncate => {
+                                self.warnings.push(
+                                    LexerWarning::IdentifierTruncated {
+                                        line : self.line,
+                                        col  : self.col,
+                                        limit: max,
+                                 });
+                                 truncated = Some(text[..max].to_string());
+                            },
+                        }
+                    }}
+                    let mut token = self.token(token, text);
+                    if let Some(name) = truncated {
+                        token.text = Cow::Owned(name);
+                    }
+                    ret = Some(token);
+                    self.offset += end;
+                    self.col    += end;
+                    break 'outer; 
+                },
+                '0' if self.look_ahead(1) == Some('b') 
+                       && matches!(self.look_ahead(2), Some('0'..='1')) => {
+                    // BitStringLiteral.
+                    
+                    self.next_char();
+                    let mut end = 2;
+                    while let Some('0'..='1') | Some('_') = self.look_ahead(1) {
+                        self.next_char();
+                        end += 1;
+                    }
+                    let off  = self.offset;
+                    let text = &self.text[off..off + end];
+                    ret = Some(self.token(BitStringLiteral, text));
+                    self.offset += end;
+                    self.col    += end;
+                    break 'outer;
+                },
+                '0'..='9' => {
+                    // NumericLiteral or FloatLiteral. A decimal separator 
+                    // only belongs to the number if it directly follows a 
+                    // digit and a digit directly follows it, so `1 .2` and
+                    // `1. 2` are both `1`, `Dot`, `2`. At most one is taken:
+                    // `1.2.3` is `1.2`, `Dot`, `3`.
+                    
+                    let mut end   = 1;
+                    let mut type_ = NumericLiteral;
+                    while let Some(la) = self.look_ahead(1) {
+                        match la {
+                            '0'..='9' => {},
+                            _ if la == self.decimal_sep 
+                                 && type_ == NumericLiteral => {
+                                match self.look_ahead(2) {
+                                    Some('0'..='9') => type_ = FloatLiteral,
+                                    _               => break,
+                                }
+                            },
+                            _ => break,
+                        }
+                        self.next_char();
+                        end += 1;
+                    }
+                    let off  = self.offset;
+                    let text = &self.text[off..off + end];
+                    if self.strict_leading_zero 
+                       && type_ == NumericLiteral
+                       && text.len() > 1 && text.starts_with('0') {
+                        self.status = Error(
+                            LeadingZero { 
+                                message: format!("Leading zero in integer, \
+                                                 '{}'.", text),
+                                line: self.line,
+                                col : self.col,
+                                span: Span::new(off, off + end),
+                         });
+                         break 'outer;
+                    }
+                    if !self.units.is_empty() {
+                        // A unit suffix, e.g. `10px`.
+                        
+                        let rest = &self.text[off + end..];
+                        let len  = rest.find(|c: char| !is_unit_char(c))
+                                       .unwrap_or(rest.len());
+                        let unit = &rest[..len];
+                        if self.units.contains(unit) {
+                            for _ in unit.chars() {
+                                self.next_char();
+                            }
+                            end += len;
+                        } else if len > 0 && self.strict_units {
+                            self.status = Error(
+                                UnknownUnit { 
+                                    message: format!("Unknown unit, '{}'.", 
+                                                     unit),
+                                    line: self.line,
+                                    col : self.col + end,
+                                    span: Span::new(off + end, 
+                                                    off + end + len),
+                             });
+                             break 'outer;
+                        }
+                    }
+                    let text = &self.text[off..off + end];
+                    ret = Some(self.token(type_, text));
+                    self.advance(end);
+                    break 'outer;
+                },
+                ',' => {
+                    let off = self.offset;
+                    ret = Some(self.token(Comma, &self.text[off..off + 1]));
+                    self.offset += 1;
+                    self.col    += 1;
+                    break 'outer;
+                },
+                '.' => {
+                    // Dot, DotDot, DotDotDot, or DotDotEq, longest first.
+                    
+                    let (type_, end) = match (self.look_ahead(1), 
+                                              self.look_ahead(2)) {
+                        (Some('.'), Some('.')) => (DotDotDot, 3),
+                        (Some('.'), Some('=')) => (DotDotEq,  3),
+                        (Some('.'), _        ) => (DotDot,    2),
+                        _                      => (Dot,       1),
+                    };
+                    for _ in 1..end {
+                        self.next_char();
+                    }
+                    let off = self.offset;
+                    ret = Some(self.token(type_, &self.text[off..off + end]));
+                    self.offset += end;
+                    self.col    += end;
+                    break 'outer;
+                },
+                _ => {
+                    // Uh oh!
+                    
+                    self.status = Error(
+                        UnrecognizedStart { 
+                            message: format!("Unrecognized start \
+                                             character, '{}'.", 
+                                             ch),
+                            line: self.line,
+                            col : self.col,
+                            span: Span::new(self.offset, 
+                                            self.offset + ch.len_utf8()),
+                     });
+                     break 'outer;
+                },
+            }
+        }
+        ret
+    }
+    
+    /// Advances the annotation state machine with the type and span of the 
+    /// token just produced, or `None` at the end, recording the span of each
+    /// complete annotation.
+    ///
+    fn track_annotation(&mut self, token: Option<(TokenType, Span)>)
+    {
+        use Annotation::*;
+        use TokenType::*;
+        
+        let state = match (self.annotation, token) {
+            (Args(start, depth), Some((type_, span))) => {
+                if type_.is_open_delimiter() {
+                    Args(start, depth + 1)
+                } else if type_.is_close_delimiter() && depth == 1 {
+                    self.annotation_spans.push(Span::new(start, span.end));
+                    Idle
+                } else if type_.is_close_delimiter() {
+                    Args(start, depth - 1)
+                } else {
+                    Args(start, depth)
+                }
+            },
+            (Sigil(start), Some((Identifier, span))) 
+          | (Sigil(start), Some((Keyword,    span))) => Name(start, span.end),
+            (Name(start, _), Some((LParen, _))) => Args(start, 1),
+            (Name(start, end), token) => {
+                self.annotation_spans.push(Span::new(start, end));
+                match token {
+                    Some((At, span)) => Sigil(span.start),
+                    _                => Idle,
+                }
+            },
+            (_, Some((At, span))) => Sigil(span.start),
+            _ => Idle,
+        };
+        self.annotation = state;
+    }
+    
+    /// Scans the rest of a string literal whose opening `"` has just been 
+    /// consumed. `end` is the length in bytes of the current token up to and 
+    /// including that quote. Escapes are validated along the way. Returns the
+    /// length of the token through the closing quote.
+    ///
+    fn scan_string(&mut self, mut end: usize) -> Result<usize, LexerError>
+    {
+        use LexerError::*;
+        let mut escaped = false;
+        let mut error   = None;
+        
+        // An invalid escape is reported once the rest of the string has been
+        // scanned, so that recovery can resume after the string.
+        while let Some(ch) = self.next_char() {
+            end += ch.len_utf8();
+            match ch {
+                '\\' if !escaped => { 
+                    if let Some(la) = self.look_ahead(1) {
+                    if la != '"' && la != '\\' && error.is_none() {
+                        error = Some(InvalidEscape { 
+                            message: format!("Invalid escape in string, \
+                                             \"\\{}\".", la),
+                            line: self.line,
+                            col : self.col + self.width(end),
+                            span: Span::new(self.offset + end, 
+                                            self.offset + end 
+                                            + la.len_utf8()),
+                        });
+                    }}
+                    escaped = true;
+                },
+                '"' if !escaped => { 
+                    return match error {
+                        Some(e) => Err(e),
+                        None    => Ok(end),
+                    };
+                },
+                _               => { escaped = false; },
+            }
+        }
+        Err(error.unwrap_or(Unterminated { 
+            message: "Unterminated string literal.".to_string(),
+            line: self.line,
+            col : self.col,
+            span: Span::new(self.offset, self.consumed()),
+        }))
+    }
+    
+    /// Scans the rest of a byte string literal, `b"..."`, whose opening quote
+    /// has just been consumed. This works like `scan_string()`, except the 
+    /// contents must be ASCII and the escapes are byte escapes: `\xNN`, `\n`,
+    /// `\r`, `\t`, `\0`, `\\`, and `\"`.
+    ///
+    fn scan_byte_string(&mut self, mut end: usize) 
+        -> Result<usize, LexerError>
+    {
+        use LexerError::*;
+        let mut error = None;
+        
+        while let Some(ch) = self.next_char() {
+            end += ch.len_utf8();
+            let bad = match ch {
+                '"'  => { 
+                    return match error {
+                        Some(e) => Err(e),
+                        None    => Ok(end),
+                    };
+                },
+                '\\' => {
+                    let col   = self.col + self.width(end);
+                    let start = self.offset + end;
+                    let esc   = match self.next_char() {
+                        Some('x') => {
+                            end += 1;
+                            let hi = self.look_ahead(1);
+                            let lo = self.look_ahead(2);
+                            if hi.is_some_and(|c| c.is_ascii_hexdigit())
+                               && lo.is_some_and(|c| c.is_ascii_hexdigit()) {
+                                self.next_char();
+                                self.next_char();
+                                end += 2;
+                                None
+                            } else {
+                                Some("\\x".to_string())
+                            }
+                        },
+                        Some(c @ 'n') | Some(c @ 'r') | Some(c @ 't') 
+                      | Some(c @ '0') | Some(c @ '\\') | Some(c @ '"') => {
+                            end += c.len_utf8();
+                            None
+                        },
+                        Some(c) => { 
+                            end += c.len_utf8(); 
+                            Some(format!("\\{}", c))
+                        },
+                        None    => break,
+                    };
+                    esc.map(|esc| (format!("Invalid escape in byte string, \
+                                            \"{}\".", esc), 
+                                   col,
+                                   Span::new(start, self.offset + end)))
+                },
+                _ if !ch.is_ascii() => {
+                    let col  = self.col + self.width(end) - 1;
+                    let span = Span::new(self.offset + end - ch.len_utf8(), 
+                                         self.offset + end);
+                    Some((format!("Non-ASCII character in byte string, \
+                                   '{}'.", ch), col, span))
+                },
+                _ => None,
+            };
+            if let Some((message, col, span)) = bad {
+                if error.is_none() {
+                    error = Some(InvalidEscape { 
+                        message, 
+                        line: self.line, 
+                        col, 
+                        span,
+                    });
+                }
+            }
+        }
+        Err(error.unwrap_or(Unterminated { 
+            message: "Unterminated byte string literal.".to_string(),
+            line: self.line,
+            col : self.col,
+            span: Span::new(self.offset, self.consumed()),
+        }))
+    }
+    
+    /// Returns the width in characters of the first `end` bytes of the text
+    /// from the current offset.
+    ///
+    fn width(&self, end: usize) -> usize
+    {
+        self.text[self.offset..self.offset + end].chars().count()
+    }
+    
+    /// Returns the line and column offsets of a byte offset into the text. 
+    /// This counts from the start of the text, so it's meant for reporting
+    /// errors.
+    ///
+    fn position_of(&self, offset: usize) -> (usize, usize)
+    {
+        let before = &self.text[..offset];
+        let line   = before.chars().filter(|&c| self.is_line_break(c)).count();
+        let start  = self.line_start(offset);
+        (line, before[start..].chars().count())
+    }
+    
+    /// Returns the length in characters of the current line up to `offset`,
+    /// not counting the `\r` of a CRLF.
+    ///
+    fn line_chars(&self, offset: usize) -> usize
+    {
+        let line = &self.text[self.line_head..offset];
+        line.strip_suffix('\r').unwrap_or(line).chars().count()
+    }
+    
+    /// Returns the offset of the start of the line that `offset` is on.
+    ///
+    fn line_start(&self, offset: usize) -> usize
+    {
+        let before = &self.text[..offset];
+        before.char_indices()
+              .rfind(|&(_, c)| self.is_line_break(c))
+              .map_or(0, |(i, c)| i + c.len_utf8())
+    }
+    
+    /// Returns `true` if `ch` breaks lines: `\n`, or one of the characters 
+    /// added with `line_break_chars()`.
+    ///
+    fn is_line_break(&self, ch: char) -> bool
+    {
+        ch == '\n' || self.line_breaks.contains(&ch)
+    }
+    
+    /// Checks the text consumed since the last check for a character past 
+    /// column `limit` on a line that hasn't been reported yet. The offset,
+    /// line, and character column reached are kept, so each character is
+    /// only looked at once.
+    ///
+    fn line_too_long(&mut self, limit: usize) -> Option<LexerError>
+    {
+        let end   = self.consumed();
+        let text  = self.text;
+        let mut found = None;
+        let (from, mut line, mut col) = self.line_scan;
+        
+        if end <= from {
+            return None;
+        }
+        for (i, ch) in text[from..end].char_indices() {
+            let crlf = ch == '\r' && text[from + i + 1..].starts_with('\n');
+            if self.is_line_break(ch) {
+                line += 1;
+                col   = 0;
+                continue;
+            }
+            if crlf {
+                continue;
+            }
+            if col >= limit && found.is_none() 
+               && self.long_line.is_none_or(|l| l < line) {
+                self.long_line = Some(line);
+                let span = Span::new(from + i, from + i + ch.len_utf8());
+                found = Some(LexerError::LineTooLong { line, col, limit, 
+                                                       span });
+            }
+            col += 1;
+        }
+        self.line_scan = (end, line, col);
+        found
+    }
+    
+    /// Queues up the tokens of a heredoc body, which starts at the current 
+    /// position and ends with a line that is just `tag`, and moves past it.
+    ///
+    fn heredoc_body(&mut self, tag: &str, quoted: bool)
+    {
+        use TokenType::*;
+        let body_start = self.offset;
+        let text       = self.text;
+        let (end, term) = heredoc_end(&text[body_start..], tag, 
+                                      |c| self.is_line_break(c))
+                              .unwrap_or_default();
+        let body       = &text[body_start..body_start + end];
+        
+        let mut pieces = Vec::new();
+        if quoted {
+            pieces.push((Heredoc, 0, end));
+        } else {
+            let mut start = 0;
+            let mut iter  = body.char_indices().peekable();
+            while let Some((i, ch)) = iter.next() {
+                if ch == '\\' && iter.peek().is_some_and(|&(_, c)| c == '$') {
+                    iter.next();
+                    continue;
+                }
+                if ch != '$' {
+                    continue;
+                }
+                let rest = &body[i + 1..];
+                let len  = if rest.starts_with('{') {
+                    rest.find('}').map_or(0, |j| j + 1)
+                } else if rest.starts_with(|c: char| c.is_alphabetic() 
+                                                     || c == '_') {
+                    rest.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len())
+                } else {
+                    0
+                };
+                if len > 0 {
+                    if start < i {
+                        pieces.push((Heredoc, start, i));
+                    }
+                    pieces.push((Interpolation, i, i + 1 + len));
+                    start = i + 1 + len;
+                    while iter.peek().is_some_and(|&(j, _)| j < start) {
+                        iter.next();
+                    }
+                }
+            }
+            if start < end {
+                pieces.push((Heredoc, start, end));
+            }
+        }
+        pieces.push((HeredocEnd, end, end + tag.len()));
+        
+        for (type_, start, stop) in pieces {
+            self.seek(body_start + start);
+            let token = self.token(type_, &text[body_start + start
+                                                ..body_start + stop]);
+            self.pending.push_back(token);
+        }
+        self.seek(body_start + term);
+    }
+    
+    /// Returns the column after `ch` at column `col`, taking tab stops into
+    /// account if there's a tab width.
+    ///
+    fn tab_stop(&self, col: usize, ch: char) -> usize
+    {
+        match self.tab_width {
+            Some(width) if ch == '\t' => (col / width + 1) * width,
+            _                         => col + 1,
+        }
+    }
+    
+    /// Returns `true` if the whitespace-delimited word at the current 
+    /// position is a number, i.e. digits with at most decimal separators.
+    ///
+    fn number_ahead(&self) -> bool
+    {
+        let rest = &self.text[self.offset..];
+        let word = &rest[..rest.find(char::is_whitespace)
+                               .unwrap_or(rest.len())];
+        word.starts_with(|c: char| c.is_ascii_digit())
+        && word.chars().all(|c| c.is_ascii_digit() || c == self.decimal_sep)
+    }
+    
+    /// Returns the length of the Lisp-style symbol at the current position, 
+    /// or zero if there's none or symbols aren't enabled. A symbol stands 
+    /// alone, with whitespace or a delimiter on each side, so `a+ b` is still
+    /// an operator.
+    ///
+    fn symbol_len(&self) -> usize
+    {
+        let chars = match &self.symbol_chars {
+            Some(chars) => chars,
+            None        => return 0,
+        };
+        match self.text[..self.offset].chars().next_back() {
+            Some(c) if !c.is_whitespace() && !"([{".contains(c) => return 0,
+            _                                                   => {},
+        }
+        let rest = &self.text[self.offset..];
+        let len  = rest.find(|c: char| !chars.contains(c))
+                       .unwrap_or(rest.len());
+        match rest[len..].chars().next() {
+            Some(c) if !c.is_whitespace() && !"()[]{}\";".contains(c) => 0,
+            _                                                       => len,
+        }
+    }
+    
+    /// Returns `true` if a line comment starts at the current position.
+    ///
+    fn comment_ahead(&self) -> bool
+    {
+        let rest = &self.text[self.offset..];
+        self.line_comments.iter().any(|m| rest.starts_with(m.as_str()))
+    }
+    
+    /// Having just consumed `ch`, checks whether it starts a parameter 
+    /// placeholder in one of the enabled styles.
+    ///
+    fn parameter_ahead(&mut self, ch: char) -> bool
+    {
+        use ParamStyle::*;
+        let la = self.look_ahead(1);
+        match ch {
+            '?' => self.param_styles.contains(&Question),
+            '$' => self.param_styles.contains(&Numbered)
+                   && la.is_some_and(|c| c.is_ascii_digit()),
+            ':' => self.param_styles.contains(&Named)
+                   && la.is_some_and(|c| c.is_ascii_alphabetic() || c == '_'),
+            '@' => self.param_styles.contains(&At)
+                   && la.is_some_and(|c| c.is_ascii_alphabetic() || c == '_'),
+            _   => false,
+        }
+    }
+    
+    /// Checks whether the text at the current offset starts with the opening
+    /// verbatim marker, if one is set.
+    ///
+    fn verbatim_ahead(&self) -> bool
+    {
+        match &self.verbatim {
+            Some((open, _)) => self.text[self.offset..].starts_with(open),
+            None            => false,
+        }
+    }
+    
+    /// Returns the scan function of the first user rule that accepts `ch`.
+    ///
+    fn rule_for(&self, ch: char) 
+        -> Option<fn(&mut Scanner<'_, 'input>) -> Token<'input>>
+    {
+        self.rules.iter().find(|rule| (rule.0)(ch)).map(|rule| rule.1)
+    }
+    
+    /// Having just consumed an `r`, checks whether a raw string follows: some
+    /// number of `#`'s and then a `"`. Returns the number of `#`'s if so.
+    ///
+    fn raw_hashes(&mut self) -> Option<usize>
+    {
+        let mut hashes = 0;
+        loop {
+            match self.look_ahead(hashes + 1) {
+                Some('#') => { hashes += 1; },
+                Some('"') => { return Some(hashes); },
+                _         => { return None; },
+            }
+        }
+    }
+    
+    /// Returns `true` if a `/` at the current position would start a regex
+    /// literal rather than be the division operator. That's the case at the 
+    /// start of input and after tokens that can't end an operand.
+    ///
+    fn regex_allowed(&self) -> bool
+    {
+        use TokenType::*;
+        match self.prev_type {
+            None => true,
+            Some(type_) => !matches!(type_, Identifier     | StringLiteral 
+                                          | NumericLiteral | FloatLiteral 
+                                          | RegexLiteral   | RParen 
+                                          | RBrace         | RBracket),
+        }
+    }
+}
+             
+/// Enables the lexer to be used as an iterator in loops.
+/// 
+impl<'input> Iterator for Lexer<'input>
+{
+    type Item = Token<'input>;
+    
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        if self.stop_on_error {
+            return self.next_token();
+        }
+        // Errors are recorded by `next_recovering()`; the iterator skips them.
+        while let Some(result) = self.next_recovering() {
+            if let Ok(token) = result {
+                return Some(token);
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over the tokens of a lexer with a payload attached to each by
+/// a closure. Created by `Lexer::with_token_data()`.
+///
+pub struct WithTokenData<'input, F>
+{
+    lexer : Lexer<'input>,
+    f     : F,
+}
+
+impl<'input, F> WithTokenData<'input, F>
+{
+    /// Returns the underlying lexer, e.g. to check its status.
+    ///
+    pub fn lexer(&self) -> &Lexer<'input>
+    {
+        &self.lexer
+    }
+}
+
+impl<'input, T, F> Iterator for WithTokenData<'input, F>
+where
+    F: FnMut(&Token<'input>) -> T,
+{
+    type Item = Token<'input, T>;
+    
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        let token = self.lexer.next()?;
+        let data  = (self.f)(&token);
+        Some(token.with_data(data))
+    }
+}
+
+/// A source of tokens that can be driven without knowing its concrete type,
+/// so differently configured lexers, or other token producers, can be 
+/// swapped at runtime behind a `Box<dyn TokenSource>`.
+///
+pub trait TokenSource<'input>: Iterator<Item = Token<'input>>
+{
+    /// Returns the status of the source, as `Lexer::status()` does.
+    ///
+    fn status(&self) -> &LexerStatus;
+}
+
+impl<'input> TokenSource<'input> for Lexer<'input>
+{
+    fn status(&self) -> &LexerStatus
+    {
+        Lexer::status(self)
+    }
+}
+
+
+/// Describes an edit to a text in byte offsets: the `old_len` bytes at `start`
+/// were replaced by `new_len` bytes.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edit
+{
+    pub start   : usize,
+    pub old_len : usize,
+    pub new_len : usize,
+}
+
+/// Brings `tokens`, produced by a default `Lexer` from a text, up to date 
+/// with `source`, the text after `edit`. Only the region affected by the edit
+/// is scanned again: from the start of the line the edit begins on, until the
+/// scan falls back in step with the old tokens after the edit. The new tokens
+/// are spliced in, and the ones after them have their positions adjusted.
+/// The result is the same as scanning all of `source`; if that would end in
+/// an error, the tokens end where the error occurs and the error is returned.
+/// If the old tokens stop short of the end of the old text, because its scan
+/// ended in an error, all of `source` is scanned again.
+///
+pub fn incremental_update<'input>(tokens : &mut Vec<Token<'input>>, 
+                                  source : &'input str, 
+                                  edit   : Edit) 
+    -> Result<(), LexerError>
+{
+    let old_end = edit.start + edit.old_len;
+    let shifted = |offset: usize| offset + edit.new_len - edit.old_len;
+    
+    // Tokens before the edit may scan differently with the new text after
+    // them, e.g. `1.` followed by `5`, so the whole line is scanned again, 
+    // along with any token running into it.
+    let line    = source[..edit.start].rfind('\n').map_or(0, |i| i + 1);
+    let first   = tokens.partition_point(|t| t.span.end < line);
+    let restart = tokens.get(first).map_or(line, |t| t.span.start.min(line));
+    
+    if first == tokens.len() {
+        let end = tokens.last().map_or(0, |t| t.span.end);
+        
+        if !scans_empty(&source[end..line]) {
+            return rescan(tokens, source);
+        }
+    }
+    let mut lexer = Lexer::new(source);
+    lexer.seek(restart);
+    
+    let mut fresh = Vec::new();
+    let mut sync  = None;
+    let mut k     = first;
+    
+    while let Some(token) = lexer.next_token() {
+        while k < tokens.len() 
+              && (tokens[k].span.start < old_end 
+                  || shifted(tokens[k].span.start) < token.span.start) {
+            k += 1;
+        }
+        if k < tokens.len() 
+           && shifted(tokens[k].span.start) == token.span.start
+           && tokens[k].same_lexeme(&token) {
+            sync = Some((k, token));
+            break;
+        }
+        fresh.push(token);
+    }
+    let mut tail   = Vec::new();
+    let     synced = sync.is_some();
+    
+    if let Some((k, token)) = sync {
+        let sync_line  = tokens[k].line;
+        let line_delta = token.line as isize - sync_line as isize;
+        let col_delta  = token.col  as isize - tokens[k].col as isize;
+        
+        for mut old in tokens.drain(k..) {
+            old.span = Span::new(shifted(old.span.start), 
+                                 shifted(old.span.end));
+            if old.line == sync_line {
+                old.col = (old.col as isize + col_delta) as usize;
+            }
+            old.line = (old.line as isize + line_delta) as usize;
+            if let Cow::Borrowed(_) = old.text {
+                old.text = Cow::Borrowed(&source[old.span.start
+                                                 ..old.span.end]);
+            }
+            tail.push(old);
+        }
+    }
+    tokens.truncate(first);
+    tokens.extend(fresh);
+    tokens.extend(tail);
+    
+    if synced {
+        let end = tokens.last().map_or(0, |t| t.span.end);
+        
+        if !scans_empty(&source[end..]) {
+            return rescan(tokens, source);
+        }
+    }
+    match lexer.status {
+        LexerStatus::Error(e) => Err(e),
+        _                     => Ok(()),
+    }
+}
+
+/// Whether `text` holds nothing a default `Lexer` turns into a token or an
+/// error, i.e. whether the old tokens could have skipped over it.
+///
+fn scans_empty(text: &str) -> bool
+{
+    let mut lexer = Lexer::new(text);
+    
+    lexer.next_token().is_none() 
+        && !matches!(lexer.status, LexerStatus::Error(_))
+}
+
+/// Replaces `tokens` with those from a fresh scan of all of `source`.
+///
+fn rescan<'input>(tokens : &mut Vec<Token<'input>>, 
+                  source : &'input str) 
+    -> Result<(), LexerError>
+{
+    let mut lexer = Lexer::new(source);
+    
+    tokens.clear();
+    tokens.extend(&mut lexer);
+    
+    match lexer.status {
+        LexerStatus::Error(e) => Err(e),
+        _                     => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use TokenType::*;
+    
+    fn types(lexer: Lexer<'_>) -> Vec<(TokenType, String)>
+    {
+        lexer.map(|t| (t.token_type(), t.text().to_string())).collect()
+    }
+    
+    fn pairs(expected: &[(TokenType, &str)]) -> Vec<(TokenType, String)>
+    {
+        expected.iter().map(|&(ty, s)| (ty, s.to_string())).collect()
+    }
+    
+    #[test]
+    fn decimal_separator_changes_float_syntax()
+    {
+        let lexer = Lexer::new("3,14").decimal_separator(',');
+        assert_eq!(types(lexer), pairs(&[(FloatLiteral, "3,14")]));
+    
+        assert_eq!(types(Lexer::new("3.14, 2.")),
+                   pairs(&[(FloatLiteral,   "3.14"), (Comma, ","),
+                           (NumericLiteral, "2"),    (Dot,   ".")]));
+    
+        let lexer = Lexer::new("3.14").decimal_separator(',');
+        assert_eq!(types(lexer),
+                   pairs(&[(NumericLiteral, "3"), (Dot, "."),
+                           (NumericLiteral, "14")]));
+    }
+    
+    #[test]
+    fn stream_to_writes_one_line_per_token()
+    {
+        let mut out    = Vec::new();
+        let     status = Lexer::new("x = 1;\nfoo").stream_to(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "0:0 Identifier \"x\"\n0:2 Operator \"=\"\n\
+                    0:4 NumericLiteral \"1\"\n0:5 Semicolon \";\"\n\
+                    1:0 Identifier \"foo\"\n");
+        assert!(matches!(status, LexerStatus::EndOfStream));
+    }
+    
+    #[test]
+    fn source_map_reports_original_spans()
+    {
+        let mut map = SourceMap::new("a /*xx*/ b\nc");
+        map.add_removal(2, 6);
+        let tokens: Vec<_> = Lexer::new("a  b\nc").source_map(map).collect();
+        assert_eq!(tokens[0].span(), Span::new(0, 1));
+        assert_eq!(tokens[1].span(), Span::new(9, 10));
+        assert_eq!(tokens[1].pos(),  (0, 9));
+        assert_eq!(tokens[2].span(), Span::new(11, 12));
+        assert_eq!(tokens[2].pos(),  (1, 0));
+    }
+    
+    #[test]
+    fn error_on_type_stops_at_disallowed_token()
+    {
+        let mut lexer = Lexer::new("a + b").error_on_type(Operator);
+        assert_eq!((&mut lexer).count(), 1);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::Disallowed {
+                             type_: Operator, line: 0, col: 2, .. })));
+    }
+    
+    #[test]
+    fn regex_literals_depend_on_previous_token()
+    {
+        let lexer = Lexer::new("x = /ab+c/gi").regex_literals(true);
+        assert_eq!(types(lexer),
+                   pairs(&[(Identifier, "x"), (Operator, "="),
+                           (RegexLiteral, "/ab+c/gi")]));
+    
+        let lexer = Lexer::new("a / b").regex_literals(true);
+        assert_eq!(types(lexer),
+                   pairs(&[(Identifier, "a"), (Operator, "/"),
+                           (Identifier, "b")]));
+    
+        let lexer = Lexer::new("(/[/]\\//)").regex_literals(true);
+        assert_eq!(types(lexer),
+                   pairs(&[(LParen, "("), (RegexLiteral, "/[/]\\//"),
+                           (RParen, ")")]));
+    
+        let mut lexer = Lexer::new("/abc").regex_literals(true);
+        assert_eq!((&mut lexer).count(), 0);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::Unterminated { .. })));
+    }
+    
+    fn longest_line(mut lexer: Lexer<'_>) -> usize
+    {
+        (&mut lexer).for_each(drop);
+        lexer.max_line_length()
+    }
+    
+    #[test]
+    fn max_line_length_counts_characters()
+    {
+        let text = "a = 1;\nfoo_bar = \"hello\";\nb";
+        assert_eq!(longest_line(Lexer::new(text)),        18);
+        assert_eq!(longest_line(Lexer::new("a\nabcdef")), 6);
+        assert_eq!(longest_line(Lexer::new("abc\r\nx")),  3);
+        assert_eq!(longest_line(Lexer::new("\tabc").tab_width(8)), 4);
+    }
+    
+    #[test]
+    fn atoms_keep_quoted_spaces()
+    {
+        let lexer = Lexer::new("foo \"bar baz\" qux").atoms(true);
+        assert_eq!(types(lexer),
+                   pairs(&[(Atom, "foo"), (Atom, "\"bar baz\""),
+                           (Atom, "qux")]));
+    
+        let mut lexer = Lexer::new("\"abc").atoms(true);
+        assert_eq!((&mut lexer).count(), 0);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::Unterminated { .. })));
+    }
+    
+    #[test]
+    fn string_escapes_are_checked()
+    {
+        assert_eq!(types(Lexer::new("x = \"h\\\\\\\"i\";")),
+                   pairs(&[(Identifier, "x"), (Operator, "="),
+                           (StringLiteral, "\"h\\\\\\\"i\""),
+                           (Semicolon, ";")]));
+    
+        let mut lexer = Lexer::new("\"a\\qb\"");
+        assert_eq!((&mut lexer).count(), 0);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::InvalidEscape {
+                             col: 3, .. })));
+    }
+    
+    type Placed = Vec<(String, (usize, usize))>;
+    
+    fn texts(lexer: &mut Lexer<'_>) -> Placed
+    {
+        lexer.map(|t| (t.text().to_string(), t.pos())).collect()
+    }
+    
+    #[test]
+    fn skip_unrecognized_resumes_after_bad_input()
+    {
+        let mut lexer = Lexer::new("a @ foo");
+        assert_eq!(texts(&mut lexer), [("a".to_string(), (0, 0))]);
+        assert!(lexer.skip_unrecognized());
+        assert_eq!(texts(&mut lexer), [("foo".to_string(), (0, 4))]);
+        assert!(!lexer.skip_unrecognized());
+    }
+    
+    #[test]
+    fn same_lexeme_compares_type_and_text()
+    {
+        let tokens: Vec<_> = Lexer::new("x = x;\n\"x\"").collect();
+        assert!(tokens[0].same_lexeme(&tokens[2]));
+        assert!(!tokens[0].same_lexeme(&tokens[1]));
+        assert!(!tokens[0].same_lexeme(&tokens[4]));
+    }
+    
+    fn recover(lexer: &mut Lexer<'_>) -> (Placed, usize)
+    {
+        let mut tokens = Vec::new();
+        let mut errors = 0;
+        while let Some(result) = lexer.next_recovering() {
+            match result {
+                Ok(t)  => tokens.push((t.text().to_string(), t.pos())),
+                Err(_) => errors += 1,
+            }
+        }
+        (tokens, errors)
+    }
+    
+    #[test]
+    fn max_errors_limits_recovery()
+    {
+        let text      = "a @ @ \"bad\\q\" b @ @ @ @ @ @ @ @";
+        let mut lexer = Lexer::new(text).max_errors(5);
+        assert_eq!(recover(&mut lexer).1, 5);
+        assert_eq!(lexer.errors().len(),  5);
+        assert!(matches!(lexer.status(), LexerStatus::TooManyErrors));
+    
+        let mut lexer = Lexer::new("a @ b\n@ c");
+        assert_eq!(recover(&mut lexer),
+                   (vec![("a".to_string(), (0, 0)),
+                         ("b".to_string(), (0, 4)),
+                         ("c".to_string(), (1, 2))], 2));
+        assert!(matches!(lexer.status(), LexerStatus::EndOfStream));
+    }
+    
+    #[test]
+    fn byte_strings_and_bit_strings()
+    {
+        assert_eq!(types(Lexer::new("b\"abc\" b")),
+                   pairs(&[(ByteStringLiteral, "b\"abc\""),
+                           (Identifier, "b")]));
+        assert_eq!(types(Lexer::new("b\"\\x41\\n\"")),
+                   pairs(&[(ByteStringLiteral, "b\"\\x41\\n\"")]));
+        assert_eq!(types(Lexer::new("0b1010 0b2")),
+                   pairs(&[(BitStringLiteral, "0b1010"), (NumericLiteral, "0"),
+                           (Identifier, "b"), (NumericLiteral, "2")]));
+    
+        let mut lexer = Lexer::new("b\"\\u{41}\" x");
+        assert_eq!((&mut lexer).count(), 0);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::InvalidEscape {
+                             col: 3, .. })));
+    
+        let mut lexer = Lexer::new("b\"\u{e9}\"");
+        assert_eq!((&mut lexer).count(), 0);
+    
+        let mut lexer = Lexer::new("b\"\\x4\" z");
+        assert_eq!(recover(&mut lexer),
+                   (vec![("z".to_string(), (0, 7))], 1));
+    }
+    
+    #[test]
+    fn warn_trailing_comma_before_close()
+    {
+        let mut lexer = Lexer::new("[1, 2, ] (a,b)").warn_trailing_comma(true);
+        assert_eq!((&mut lexer).count(), 11);
+        assert_eq!(lexer.warnings().len(), 1);
+        assert!(matches!(lexer.warnings()[0],
+                         LexerWarning::TrailingComma { line: 0, col: 5 }));
+    }
+    
+    #[test]
+    fn raw_strings_span_lines()
+    {
+        let text  = "x = r\"line1\nline2\" y\n z r#\"a\"b\"# r";
+        let found = Lexer::new(text).map(|t| (t.token_type(), t.pos()))
+                                    .collect::<Vec<_>>();
+        assert_eq!(found,
+                   [(Identifier,    (0, 0)), (Operator,      (0, 2)),
+                    (StringLiteral, (0, 4)), (Identifier,    (1, 7)),
+                    (Identifier,    (2, 1)), (StringLiteral, (2, 3)),
+                    (Identifier,    (2, 12))]);
+    
+        let tokens: Vec<_> = Lexer::new("\"a\nb\" c").collect();
+        assert_eq!(tokens[1].pos(), (1, 3));
+    }
+    
+    #[test]
+    fn max_identifier_len_truncates_or_errors()
+    {
+        let policy    = LengthPolicy::Truncate;
+        let text      = "abcdefgh if x";
+        let mut lexer = Lexer::new(text).max_identifier_len(4, policy);
+        let tokens: Vec<_> = (&mut lexer).collect();
+        assert_eq!(tokens[0].text(), "abcd");
+        assert_eq!(tokens[0].span(), Span::new(0, 8));
+        assert_eq!(tokens[1].pos(),  (0, 9));
+        assert_eq!(tokens[2].pos(),  (0, 12));
+        assert!(matches!(lexer.warnings()[0],
+                         LexerWarning::IdentifierTruncated { limit: 4, .. }));
+    
+        let policy    = LengthPolicy::Error;
+        let mut lexer = Lexer::new("x abcdefgh").max_identifier_len(4, policy);
+        assert_eq!((&mut lexer).count(), 1);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::IdentifierTooLong {
+                             col: 2, .. })));
+    }
+    
+    fn is_hash(ch: char) -> bool
+    {
+        ch == '#'
+    }
+    
+    fn scan_tag<'a, 'input>(scanner: &mut Scanner<'a, 'input>) -> Token<'input>
+    {
+        while let Some(ch) = scanner.look_ahead(1) {
+            if !ch.is_alphanumeric() && ch != '_' {
+                break;
+            }
+            scanner.next_char();
+        }
+        scanner.token(Custom("tag"))
+    }
+    
+    #[test]
+    fn with_rules_scans_custom_tokens()
+    {
+        let lexer = Lexer::with_rules("x #tag_1 y", vec![(is_hash, scan_tag)]);
+        assert_eq!(types(lexer),
+                   pairs(&[(Identifier, "x"), (Custom("tag"), "#tag_1"),
+                           (Identifier, "y")]));
+    }
+    
+    #[test]
+    fn detect_bidi_reports_control_characters()
+    {
+        let text      = "x = \"abc\u{202e}def\";\ny";
+        let mut lexer = Lexer::new(text).detect_bidi(true);
+        assert_eq!((&mut lexer).count(), 2);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::BidiControl {
+                             line: 0, col: 8, codepoint: '\u{202e}', .. })));
+        assert_eq!(Lexer::new(text).count(), 5);
+    
+        let mut lexer = Lexer::new("a\n \u{2066}b").strict();
+        assert_eq!((&mut lexer).count(), 1);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::BidiControl {
+                             line: 1, col: 1, .. })));
+    }
+    
+    #[test]
+    fn detect_bidi_stops_after_max_errors()
+    {
+        let mut lexer = Lexer::new("\u{202e}").detect_bidi(true).max_errors(1);
+        assert_eq!(recover(&mut lexer), (vec![], 1));
+        assert!(matches!(lexer.status(), LexerStatus::TooManyErrors));
+    
+        let lexer = Lexer::new("\u{202e}").detect_bidi(true)
+                                          .max_errors(1)
+                                          .stop_on_error(false);
+        assert_eq!(lexer.count(), 0);
+    }
+    
+    #[test]
+    fn in_arena_tokens_outlive_their_text()
+    {
+        let arena      = Arena::new();
+        let mut tokens = Vec::new();
+        for i in 0..3 {
+            let text = format!("x{} = {};", "y".repeat(i), i);
+            tokens.extend(Lexer::in_arena(&text, &arena));
+        }
+        let texts: Vec<_> = tokens.iter().map(Token::text).collect();
+        assert_eq!(texts, ["x",   "=", "0", ";", "xy", "=", "1", ";",
+                           "xyy", "=", "2", ";"]);
+    }
+    
+    #[test]
+    fn dot_runs_take_the_longest_operator()
+    {
+        assert_eq!(types(Lexer::new("0..=9")),
+                   pairs(&[(NumericLiteral, "0"), (DotDotEq, "..="),
+                           (NumericLiteral, "9")]));
+        assert_eq!(types(Lexer::new("a...b")),
+                   pairs(&[(Identifier, "a"), (DotDotDot, "..."),
+                           (Identifier, "b")]));
+        assert_eq!(types(Lexer::new("x..y")),
+                   pairs(&[(Identifier, "x"), (DotDot, ".."),
+                           (Identifier, "y")]));
+        assert_eq!(types(Lexer::new("....1.5")),
+                   pairs(&[(DotDotDot, "..."), (Dot, "."),
+                           (FloatLiteral, "1.5")]));
+    }
+    
+    #[test]
+    fn parameter_styles_name_placeholders()
+    {
+        let lexer = Lexer::new("? $1 :name @user_id")
+                        .parameter_style(ParamStyle::Question)
+                        .parameter_style(ParamStyle::Numbered)
+                        .parameter_style(ParamStyle::Named)
+                        .parameter_style(ParamStyle::At);
+        let found = lexer.map(|t| (t.token_type(), 
+                                   t.parameter_name().map(String::from)))
+                         .collect::<Vec<_>>();
+        assert_eq!(found, [(Parameter, None),
+                           (Parameter, Some("1".to_string())),
+                           (Parameter, Some("name".to_string())),
+                           (Parameter, Some("user_id".to_string()))]);
+    
+        let mut lexer = Lexer::new("x @y").parameter_style(ParamStyle::Named);
+        assert_eq!((&mut lexer).count(), 1);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::UnrecognizedStart {
+                             .. })));
+    }
+    
+    #[test]
+    fn stop_on_error_false_keeps_iterating()
+    {
+        let mut lexer = Lexer::new("a b @ c \"\\q\" d").stop_on_error(false);
+        let texts: Vec<_> = (&mut lexer).map(|t| t.text().to_string())
+                                        .collect();
+        assert_eq!(texts, ["a", "b", "c", "d"]);
+        assert_eq!(lexer.errors().len(), 2);
+        assert_eq!(Lexer::new("a b @ c").count(), 2);
+    }
+    
+    #[test]
+    fn verbatim_delimiters_take_text_as_is()
+    {
+        let text  = "x %{ anything {} \"here\"\n }% y";
+        let lexer = Lexer::new(text).verbatim_delimiters("%{", "}%");
+        let found = lexer.map(|t| (t.token_type(), t.text().to_string(), 
+                                   t.pos()))
+                         .collect::<Vec<_>>();
+        assert_eq!(found[1], (Verbatim, " anything {} \"here\"\n ".to_string(),
+                              (0, 4)));
+        assert_eq!(found[2], (Identifier, "y".to_string(), (1, 4)));
+    
+        let mut lexer = Lexer::new("x %{ oops").verbatim_delimiters("%{", "}%");
+        assert_eq!((&mut lexer).count(), 1);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::Unterminated {
+                             line: 0, col: 2, .. })));
+    }
+    
+    #[test]
+    fn normalized_bytes_ignore_line_endings()
+    {
+        let crlf: Vec<_> = Lexer::new("x = \"a\r\nb\";").collect();
+        let lf:   Vec<_> = Lexer::new("x = \"a\nb\";").collect();
+        assert_ne!(crlf[2].text(), lf[2].text());
+        assert_eq!(crlf[2].normalized_bytes(), lf[2].normalized_bytes());
+        assert_eq!(lf[2].normalized_bytes(), b"StringLiteral\0\"a\nb\"");
+    }
+    
+    #[test]
+    fn annotations_record_spans()
+    {
+        let text      = "@Foo(1, \"x\") bar @Override baz @A(f(1)) @B";
+        let mut lexer = Lexer::new(text).annotations(true);
+        assert_eq!((&mut lexer).count(), 21);
+        let spans: Vec<_> = lexer.annotation_spans().iter()
+                                 .map(|s| &text[s.start..s.end])
+                                 .collect();
+        assert_eq!(spans, ["@Foo(1, \"x\")", "@Override", "@A(f(1))", "@B"]);
+    }
+    
+    #[test]
+    fn lone_cr_policies()
+    {
+        let text = "x \"a\rb\" y \"c\r\nd\"";
+    
+        let mut lexer = Lexer::new(text);
+        let found     = texts(&mut lexer);
+        assert_eq!(found[1].0, "\"a\rb\"");
+        assert_eq!(found[2].1, (0, 8));
+    
+        let mut lexer = Lexer::new(text).lone_cr(CrPolicy::Normalize);
+        let found     = texts(&mut lexer);
+        assert_eq!(found[1].0, "\"a\nb\"");
+        assert_eq!(found[2].1, (1, 3));
+        assert_eq!(found[3].0, "\"c\r\nd\"");
+    
+        let mut lexer = Lexer::new(text).lone_cr(CrPolicy::Error);
+        assert_eq!((&mut lexer).count(), 1);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::LoneCarriageReturn {
+                             col: 4, .. })));
+    }
+    
+    #[test]
+    fn percent_literals_close_with_partner_or_self()
+    {
+        let text  = "%w[a b] %q{h{i}} %i(x y) %q|z| x";
+        let lexer = Lexer::new(text).percent_literals(true);
+        assert_eq!(types(lexer),
+                   pairs(&[(WordListLiteral,   "%w[a b]"),
+                           (StringLiteral,     "%q{h{i}}"),
+                           (SymbolListLiteral, "%i(x y)"),
+                           (StringLiteral,     "%q|z|"),
+                           (Identifier,        "x")]));
+    
+        let lexer = Lexer::new("%q\u{2192}hi\u{2192} x").percent_literals(true);
+        assert_eq!(types(lexer),
+                   pairs(&[(StringLiteral, "%q\u{2192}hi\u{2192}"),
+                           (Identifier,    "x")]));
+    
+        let mut lexer = Lexer::new("%q{oops").percent_literals(true);
+        assert_eq!((&mut lexer).count(), 0);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::Unterminated { .. })));
+    }
+    
+    fn lexes_cleanly(mut lexer: Lexer<'_>) -> bool
+    {
+        (&mut lexer).for_each(drop);
+        !matches!(lexer.status(), LexerStatus::Error(_))
+    }
+    
+    #[test]
+    fn strict_leading_zero_rejects_padded_numbers()
+    {
+        let strict = |text| Lexer::new(text).strict_leading_zero(true);
+        assert!(lexes_cleanly(strict("0")));
+        assert!(lexes_cleanly(strict("0.5")));
+        assert!(lexes_cleanly(strict("10")));
+        assert!(!lexes_cleanly(strict("00")));
+        assert!(!lexes_cleanly(strict("0123")));
+        assert!(lexes_cleanly(Lexer::new("0123")));
+    }
+    
+    fn check_update(old: &str, start: usize, old_len: usize, insert: &str)
+    {
+        let new = format!("{}{}{}", &old[..start], insert, 
+                                    &old[start + old_len..]);
+        let edit       = Edit { start, old_len, new_len: insert.len() };
+        let mut tokens = Lexer::new(old).collect::<Vec<_>>();
+        let result     = incremental_update(&mut tokens, &new, edit);
+    
+        let mut lexer = Lexer::new(&new);
+        let expected  = (&mut lexer).collect::<Vec<_>>();
+        let show      = |tokens: &[Token<'_>]| {
+            tokens.iter().map(|t| format!("{} {:?}", t, t.span()))
+                  .collect::<Vec<_>>()
+        };
+        assert_eq!(show(&tokens), show(&expected), "{:?} -> {:?}", old, new);
+        assert_eq!(result.is_err(), 
+                   matches!(lexer.status(), LexerStatus::Error(_)));
+    }
+    
+    #[test]
+    fn incremental_update_matches_full_scan()
+    {
+        let text = "if foo_bar {\n  x = 1;\n  y = \"s\";\n}\nz";
+        check_update(text, 5,  0, "q");
+        check_update(text, 3,  0, "q");
+        check_update(text, 6,  0, "\n\n");
+        check_update(text, 0,  2, "");
+        check_update(text, 14, 5, "a+b");
+        check_update(text, 10, 0, ";");
+        check_update(text, 20, 0, "\"");
+        check_update(text, text.len(), 0, " w");
+        check_update("1.", 2, 0, "5");
+        check_update("(@", 0, 0, "(");
+        check_update("b...=)if?=@\n?", 13, 0, "\\");
+    }
+    
+    #[test]
+    fn max_line_chars_reports_each_long_line_once()
+    {
+        let text      = "a b\nabc abcdef x\nq";
+        let mut lexer = Lexer::new(text).max_line_chars(6);
+        let (tokens, errors) = recover(&mut lexer);
+        assert_eq!(errors, 1);
+        assert_eq!(tokens.iter().map(|t| t.0.as_str()).collect::<Vec<_>>(),
+                   ["a", "b", "abc", "x", "q"]);
+        assert!(matches!(lexer.errors()[0],
+                         LexerError::LineTooLong { line: 1, col: 6, 
+                                                   limit: 6, .. }));
+    
+        let mut lexer = Lexer::new("abcdefgh").max_line_chars(4);
+        assert!(lexer.next().is_none());
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::LineTooLong {
+                             line: 0, .. })));
+    
+        let lexer = Lexer::new("abc\r\nabc").max_line_chars(3);
+        assert!(lexes_cleanly(lexer));
+        let lexer = Lexer::new("abcdef\x1eabcdef").line_break_chars(&['\x1e'])
+                                                 .max_line_chars(8);
+        assert!(lexes_cleanly(lexer));
+    }
+    
+    #[test]
+    fn no_space_markers_between_touching_tokens()
+    {
+        let found = |text| Lexer::new(text).no_space_markers(true)
+                                           .map(|t| t.token_type())
+                                           .collect::<Vec<_>>();
+        assert_eq!(found("a+b"),
+                   [Identifier, NoSpace, Operator, NoSpace, Identifier]);
+        assert_eq!(found("a + b"), [Identifier, Operator, Identifier]);
+        assert_eq!(found("f(x) y"),
+                   [Identifier, NoSpace, LParen, NoSpace, Identifier, NoSpace,
+                    RParen, Identifier]);
+    }
+    
+    #[test]
+    fn line_comments_are_skipped()
+    {
+        let mut lexer = Lexer::new("x // c\ny").line_comments(&["//"]);
+        assert_eq!(texts(&mut lexer),
+                   [("x".to_string(), (0, 0)), ("y".to_string(), (1, 0))]);
+    }
+    
+    #[test]
+    fn unescaped_text_decodes_strings()
+    {
+        let tokens: Vec<_> = Lexer::new("\"hi \\\"x\\\"\" r#\"a\\b\"#")
+                                 .collect();
+        assert_eq!(tokens[0].unescaped_text().as_deref(), Some("hi \"x\""));
+        assert_eq!(tokens[1].unescaped_text().as_deref(), Some("a\\b"));
+    }
+    
+    fn kinds(lexer: Lexer<'_>) -> Vec<TokenType>
+    {
+        lexer.map(|t| t.token_type()).collect()
+    }
+    
+    #[test]
+    fn decimal_point_must_touch_digits()
+    {
+        assert_eq!(kinds(Lexer::new("1.2")),  [FloatLiteral]);
+        assert_eq!(kinds(Lexer::new("1 .2")), [NumericLiteral, Dot, 
+                                               NumericLiteral]);
+        assert_eq!(kinds(Lexer::new("1. 2")), [NumericLiteral, Dot, 
+                                               NumericLiteral]);
+    }
+    
+    #[test]
+    fn token_sources_behind_one_type()
+    {
+        let sources = vec![
+            Lexer::new("a b").into_token_source(),
+            Lexer::new("1,2").decimal_separator(',').into_token_source(),
+        ];
+        let mut counts = Vec::new();
+        for mut source in sources {
+            counts.push(source.by_ref().count());
+            assert!(matches!(source.status(), LexerStatus::EndOfStream));
+        }
+        assert_eq!(counts, [2, 1]);
+    }
+    
+    #[test]
+    fn line_start_keywords_only_at_line_start()
+    {
+        let soft = |text| kinds(Lexer::new(text)
+                                    .line_start_keywords(&["set", "run"]));
+        assert_eq!(soft("set x"),       [Keyword, Identifier]);
+        assert_eq!(soft("x set"),       [Identifier, Identifier]);
+        assert_eq!(soft("x\n  run set"), [Identifier, Keyword, Identifier]);
+    }
+    
+    #[test]
+    fn is_ascii_reflects_the_text()
+    {
+        assert!(Lexer::new("abc 1").is_ascii());
+        assert!(!Lexer::new("caf\u{e9}").is_ascii());
+        assert!(!Lexer::in_arena("\u{e9}", &Arena::default()).is_ascii());
+    }
+    
+    #[test]
+    fn word_mode_splits_on_whitespace()
+    {
+        let lexer = Lexer::new("1 2 + dup 2dup .\" x\n").word_mode(true);
+        assert_eq!(types(lexer)[..6],
+                   pairs(&[(NumericLiteral, "1"), (NumericLiteral, "2"),
+                           (Identifier, "+"),     (Identifier, "dup"),
+                           (Identifier, "2dup"),  (Identifier, ".\"")])[..]);
+    }
+    
+    #[test]
+    fn with_token_data_attaches_payloads()
+    {
+        let mut n  = 0;
+        let tokens = Lexer::new("a b c").with_token_data(|_| { n += 1; n })
+                                        .collect::<Vec<Token<'_, usize>>>();
+        assert_eq!(tokens.iter().map(|t| *t.data()).collect::<Vec<_>>(),
+                   [1, 2, 3]);
+        assert_eq!(tokens[2].text(), "c");
+    }
+    
+    #[test]
+    fn html_tags_split_markup_and_text()
+    {
+        let html = |text| types(Lexer::new(text).html_tags(true));
+        assert_eq!(html("<a href=\"x\">hi</a>"),
+                   pairs(&[(TagOpen, "<a"), (AttrName, "href"),
+                           (Operator, "="), (StringLiteral, "\"x\""),
+                           (TagEnd, ">"), (Text, "hi"), (TagClose, "</a>")]));
+        assert_eq!(html("x < y<br data-k=\"1\"/>\nz"),
+                   pairs(&[(Text, "x < y"), (TagOpen, "<br"),
+                           (AttrName, "data-k"), (Operator, "="),
+                           (StringLiteral, "\"1\""), (TagSelfClose, "/>"),
+                           (Text, "\nz")]));
+    
+        let tokens: Vec<_> = Lexer::new("<p>\nab</p>").html_tags(true)
+                                                   .collect();
+        assert_eq!(tokens[3].pos(), (1, 2));
+    }
+    
+    #[test]
+    fn numbers_take_one_decimal_point()
+    {
+        assert_eq!(types(Lexer::new("1.2.3")),
+                   pairs(&[(FloatLiteral, "1.2"), (Dot, "."),
+                           (NumericLiteral, "3")]));
+        assert_eq!(kinds(Lexer::new("1.2.3.4..5")),
+                   [FloatLiteral, Dot, FloatLiteral, DotDot, NumericLiteral]);
+    }
+    
+    #[test]
+    fn scan_until_marker_stops_at_line_start_marker()
+    {
+        let text           = "a = 1\nb ``` c\n```\nrest";
+        let (tokens, stop) = Lexer::scan_until_marker(text, "```");
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(&text[stop..], "\nrest");
+    
+        let (tokens, stop) = Lexer::scan_until_marker("x y", "```");
+        assert_eq!((tokens.len(), stop), (2, 3));
+        let (tokens, stop) = Lexer::scan_until_marker("x\n```", "```");
+        assert_eq!((tokens.len(), stop), (1, 5));
+        let (tokens, stop) = Lexer::scan_until_marker("```", "```");
+        assert_eq!((tokens.len(), stop), (0, 3));
+    
+        let lexer          = Lexer::new("x\x1e```y");
+        let (tokens, stop) = lexer.line_break_chars(&['\x1e'])
+                                  .until_marker("```");
+        assert_eq!((tokens.len(), stop), (1, 5));
+    }
+    
+    #[test]
+    fn question_mark_operators()
+    {
+        assert_eq!(types(Lexer::new("a ?? b")),
+                   pairs(&[(Identifier, "a"), (Operator, "??"),
+                           (Identifier, "b")]));
+        assert_eq!(types(Lexer::new("a?.b")),
+                   pairs(&[(Identifier, "a"), (QuestionDot, "?."),
+                           (Identifier, "b")]));
+        assert_eq!(types(Lexer::new("a?.[i]")),
+                   pairs(&[(Identifier, "a"), (QuestionDotBracket, "?.["),
+                           (Identifier, "i"), (RBracket, "]")]));
+        assert_eq!(types(Lexer::new("x?")),
+                   pairs(&[(Identifier, "x"), (Question, "?")]));
+    }
+    
+    #[test]
+    fn collect_comments_keeps_spans()
+    {
+        let text      = "a // one\nb # two";
+        let mut lexer = Lexer::new(text).line_comments(&["//", "#"])
+                                        .collect_comments(true);
+        assert_eq!(lexer.by_ref().count(), 2);
+        let comments = lexer.comments();
+        assert_eq!(comments.len(), 2);
+        assert_eq!(&text[comments[0].0.start..comments[0].0.end], "// one");
+        assert_eq!(comments[1].1, "# two");
+    }
+    
+    #[test]
+    fn identifier_convention_warns_on_mismatch()
+    {
+        let mut lexer = Lexer::new("my_var myVar\n_ok")
+                            .identifier_convention(Convention::SnakeCase);
+        assert_eq!(lexer.by_ref().count(), 3);
+        assert_eq!(lexer.warnings().len(), 1);
+        assert!(matches!(lexer.warnings()[0],
+                         LexerWarning::IdentifierCase { 
+                             line: 0, col: 7, 
+                             convention: Convention::SnakeCase }));
+        
+        assert!(Convention::PascalCase.matches("MyType"));
+        assert!(!Convention::CamelCase.matches("My_x"));
+        assert!(Convention::ScreamingSnakeCase.matches("MAX_LEN"));
+    }
+    
+    #[test]
+    fn line_continuation_joins_lines()
+    {
+        let text      = "a = 1 + \\\n 2 \\\r\n3";
+        let mut lexer = Lexer::new(text).line_continuation_char('\\');
+        let found     = texts(&mut lexer);
+        assert_eq!(found.len(), 6);
+        assert_eq!(found[4], ("2".to_string(), (1, 1)));
+        assert_eq!(found[5], ("3".to_string(), (2, 0)));
+        assert!(matches!(lexer.status(), LexerStatus::EndOfStream));
+    
+        let mut lexer = Lexer::new("a \\ b").line_continuation_char('\\');
+        assert_eq!(lexer.by_ref().count(), 1);
+        assert!(matches!(lexer.status(), LexerStatus::Error(_)));
+    }
+    
+    #[test]
+    fn lisp_symbols_stand_alone()
+    {
+        assert_eq!(types(Lexer::new("(+ 1 2)").lisp_symbols(true)),
+                   pairs(&[(LParen, "("), (Symbol, "+"), (NumericLiteral, "1"),
+                           (NumericLiteral, "2"), (RParen, ")")]));
+        assert_eq!(types(Lexer::new("(<= a b)").lisp_symbols(true))[1],
+                   (Symbol, "<=".to_string()));
+        assert_eq!(types(Lexer::new("a+b").lisp_symbols(true)),
+                   pairs(&[(Identifier, "a"), (Operator, "+"),
+                           (Identifier, "b")]));
+        assert_eq!(types(Lexer::new("a+ b").lisp_symbols(true)),
+                   pairs(&[(Identifier, "a"), (Operator, "+"),
+                           (Identifier, "b")]));
+        assert_eq!(types(Lexer::new("+ 1").lisp_symbols(true))[0],
+                   (Symbol, "+".to_string()));
+        assert_eq!(types(Lexer::new("(+ 1)").symbol_chars("*"))[1],
+                   (Operator, "+".to_string()));
+        assert_eq!(types(Lexer::new("(- x)").lisp_symbols(true))[1],
+                   (Symbol, "-".to_string()));
+    }
+    
+    #[test]
+    fn is_first_on_line_compares_lines()
+    {
+        let tokens: Vec<_> = Lexer::new("a b\nc").collect();
+        assert!(tokens[0].is_first_on_line(None));
+        assert!(!tokens[1].is_first_on_line(Some(&tokens[0])));
+        assert!(tokens[2].is_first_on_line(Some(&tokens[1])));
+    }
+    
+    #[test]
+    fn tab_width_and_expand_tabs_in_indentation()
+    {
+        let mut lexer = Lexer::new("\tfoo\n  \t x").tab_width(4)
+                                                  .expand_tabs(true);
+        let found = texts(&mut lexer);
+        assert_eq!(found, [("    ".to_string(),  (0, 0)),
+                           ("foo".to_string(),   (0, 4)),
+                           ("     ".to_string(), (1, 0)),
+                           ("x".to_string(),     (1, 5))]);
+    
+        let mut lexer = Lexer::new("\tfoo").expand_tabs(true);
+        assert_eq!(texts(&mut lexer), [(" ".repeat(8),       (0, 0)),
+                                       ("foo".to_string(),   (0, 8))]);
+    
+        let tokens: Vec<_> = Lexer::new("\t\tfoo \tx").tab_width(4).collect();
+        assert_eq!(tokens[0].pos(), (0, 8));
+        assert_eq!(tokens[1].pos(), (0, 13));
+    
+        let tokens: Vec<_> = Lexer::new("a \"b\nc\"  \tx\n\ty").tab_width(4)
+                                                             .collect();
+        assert_eq!(tokens[2].pos(), (1, 5));
+        assert_eq!(tokens[3].pos(), (2, 4));
+    }
+    
+    #[test]
+    fn error_context_marks_the_column()
+    {
+        let text      = "a\nb\nc = $\nd\ne\nf";
+        let mut lexer = Lexer::new(text);
+        (&mut lexer).for_each(drop);
+        let error = match lexer.status() {
+            LexerStatus::Error(e) => e.clone(),
+            status                => panic!("no error, {:?}", status),
+        };
+        assert_eq!(error.context(text, 1, 2),
+                   "  2 | b\n> 3 | c = $\n    |     ^\n  4 | d\n  5 | e\n");
+        assert_eq!(error.context(text, 9, 0).lines().count(), 4);
+    }
+    
+    #[test]
+    fn heredocs_queue_the_body_after_the_line()
+    {
+        let heredoc = |text| types(Lexer::new(text).heredocs(true));
+        assert_eq!(heredoc("x <<EOF y\nhi $name, ${a b} \\$ok\nEOF\nz"),
+                   pairs(&[(Identifier, "x"), (HeredocStart, "<<EOF"),
+                           (Identifier, "y"), (Heredoc, "hi "),
+                           (Interpolation, "$name"), (Heredoc, ", "),
+                           (Interpolation, "${a b}"), (Heredoc, " \\$ok\n"),
+                           (HeredocEnd, "EOF"), (Identifier, "z")]));
+        assert_eq!(heredoc("<<'EOF'\nhi $name\nEOF"),
+                   pairs(&[(HeredocStart, "<<'EOF'"), (Heredoc, "hi $name\n"),
+                           (HeredocEnd, "EOF")]));
+        assert_eq!(heredoc("<<E\nE"),
+                   pairs(&[(HeredocStart, "<<E"), (HeredocEnd, "E")]));
+    
+        let tokens: Vec<_> = Lexer::new("<<E\n a\nE\nq").heredocs(true)
+                                                       .collect();
+        assert_eq!(tokens.iter().map(Token::pos).collect::<Vec<_>>(),
+                   [(0, 0), (1, 0), (2, 0), (3, 0)]);
+    
+        let mut lexer = Lexer::new("<<E\nabc").heredocs(true);
+        assert_eq!(lexer.by_ref().count(), 0);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::Unterminated { .. })));
+    }
+    
+    #[test]
+    fn heredoc_pieces_are_checked()
+    {
+        let text      = "cat <<EOF\nhi $x\nEOF\n";
+        let mut lexer = Lexer::new(text).heredocs(true)
+                                        .error_on_type(Interpolation);
+        assert!(lexer.by_ref().all(|t| t.token_type() != Interpolation));
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::Disallowed { .. })));
+    
+        let text      = "cat <<EOF\nhi \u{202e}\nEOF\nz";
+        let mut lexer = Lexer::new(text).heredocs(true).detect_bidi(true);
+        assert_eq!(recover(&mut lexer),
+                   (vec![("cat".to_string(),   (0, 0)),
+                         ("<<EOF".to_string(), (0, 4)),
+                         ("z".to_string(),     (3, 0))], 1));
+    }
+    
+    #[test]
+    fn tokenize_into_reuses_the_buffer()
+    {
+        let mut buffer = Vec::with_capacity(16);
+        let status     = Lexer::tokenize_into("a b c", &mut buffer);
+        assert!(matches!(status, LexerStatus::EndOfStream));
+        assert_eq!(buffer.len(), 3);
+    
+        let (capacity, ptr) = (buffer.capacity(), buffer.as_ptr());
+        Lexer::tokenize_into("x y", &mut buffer);
+        assert_eq!(buffer.iter().map(Token::text).collect::<Vec<_>>(),
+                   ["x", "y"]);
+        assert_eq!((buffer.capacity(), buffer.as_ptr()), (capacity, ptr));
+    }
+    
+    #[test]
+    fn word_operators_take_precedence()
+    {
+        let lexer = Lexer::new("a and b or not if")
+                        .word_operators(&["and", "or", "not", "if"]);
+        assert_eq!(types(lexer),
+                   pairs(&[(Identifier, "a"), (Operator, "and"),
+                           (Identifier, "b"), (Operator, "or"),
+                           (Operator, "not"), (Operator, "if")]));
+    }
+    
+    #[test]
+    fn forbid_tabs_reports_each_tab()
+    {
+        let mut lexer = Lexer::new("\tfoo").forbid_tabs(true);
+        assert!(lexer.next().is_none());
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::TabCharacter {
+                             line: 0, col: 0, .. })));
+    
+        let mut lexer = Lexer::new("a \"x\ty\"\n b\t").forbid_tabs(true);
+        let errors    = std::iter::from_fn(|| lexer.next_recovering())
+                            .filter_map(|r| r.err().map(|e| e.pos()))
+                            .collect::<Vec<_>>();
+        assert_eq!(errors, [(0, 4), (1, 2)]);
+    }
+    
+    fn with_units(lexer: Lexer<'_>) -> Vec<(String, Option<String>)>
+    {
+        lexer.map(|t| (t.text().to_string(), t.unit().map(String::from)))
+             .collect()
+    }
+    
+    #[test]
+    fn units_attach_to_numbers()
+    {
+        let units = ["px", "em", "ms", "%", "\u{b5}s"];
+        assert_eq!(with_units(Lexer::new("10px 2.5em 3").units(&units)),
+                   [("10px".to_string(),  Some("px".to_string())),
+                    ("2.5em".to_string(), Some("em".to_string())),
+                    ("3".to_string(),     None)]);
+        assert_eq!(with_units(Lexer::new("10pt").units(&units)),
+                   [("10".to_string(), None), ("pt".to_string(), None)]);
+        assert_eq!(with_units(Lexer::new("10px")),
+                   [("10".to_string(), None), ("px".to_string(), None)]);
+    
+        let tokens: Vec<_> = Lexer::new("5\u{b5}s x").units(&units).collect();
+        assert_eq!(tokens[1].pos(), (0, 4));
+    
+        let mut lexer = Lexer::new("10pt").units(&units).strict_units(true);
+        assert!(lexer.next().is_none());
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::UnknownUnit {
+                             col: 2, .. })));
+    }
+    
+    #[test]
+    fn directives_are_kept_as_tokens()
+    {
+        let lexer = Lexer::new("x # noqa: E1\ny # normal")
+                        .line_comments(&["#"])
+                        .directives(&["# noqa"]);
+        assert_eq!(types(lexer),
+                   pairs(&[(Identifier, "x"), (Directive, "# noqa: E1"),
+                           (Identifier, "y")]));
+    }
+    
+    #[test]
+    fn positions_zero_and_one_based()
+    {
+        let tokens: Vec<_> = Lexer::new("a\n  b").collect();
+        assert_eq!(tokens[0].pos_zero_based(), (0, 0));
+        assert_eq!(tokens[0].pos_one_based(),  (1, 1));
+        assert_eq!(tokens[1].pos(),            tokens[1].pos_zero_based());
+        assert_eq!(tokens[1].pos_one_based(),  (2, 3));
+    }
+    
+    #[test]
+    fn chunks_group_tokens()
+    {
+        let sizes: Vec<_> = Lexer::new("a b c d e").chunks(2)
+                                                  .map(|c| c.len())
+                                                  .collect();
+        assert_eq!(sizes, [2, 2, 1]);
+        assert_eq!(Lexer::new("").chunks(3).count(), 0);
+    }
+    
+    #[test]
+    fn trailing_whitespace_lines_are_recorded()
+    {
+        let mut lexer = Lexer::new("a\nb  \nc\t\r\nd\r\ne ");
+        (&mut lexer).for_each(drop);
+        assert_eq!(lexer.trailing_whitespace_lines(), [1, 2]);
+    }
+    
+    #[test]
+    fn ternary_colons_pair_with_questions()
+    {
+        let ternary = |text| kinds(Lexer::new(text).ternary_colons(true));
+        assert_eq!(ternary("a ? b : c"),
+                   [Identifier, Question, Identifier, TernaryColon, 
+                    Identifier]);
+        assert_eq!(ternary("x: int"), [Identifier, Colon, Identifier]);
+        assert_eq!(ternary("a ? b ? c : d : e: f"),
+                   [Identifier, Question, Identifier, Question, Identifier,
+                    TernaryColon, Identifier, TernaryColon, Identifier, Colon,
+                    Identifier]);
+        assert_eq!(kinds(Lexer::new("a ? b : c")),
+                   [Identifier, Question, Identifier, Colon, Identifier]);
+    }
+    
+    #[test]
+    fn peek_byte_does_not_consume()
+    {
+        let mut lexer = Lexer::new("abc \u{e9}");
+        assert_eq!(lexer.peek_byte(), Some(b'a'));
+        lexer.next();
+        assert_eq!(lexer.peek_byte(), Some(b' '));
+        lexer.next();
+        assert_eq!(lexer.peek_byte(), None);
+    }
+    
+    #[test]
+    fn quoted_identifiers_unescape_doubled_quotes()
+    {
+        let tokens: Vec<_> = Lexer::new("`a``b` x `c`").quoted_identifiers('`')
+                                                      .collect();
+        assert_eq!((tokens[0].text(), tokens[0].span()), 
+                   ("a`b", Span::new(0, 6)));
+        assert_eq!(tokens[1].text(), "x");
+        assert_eq!((tokens[2].text(), tokens[2].span()), 
+                   ("c", Span::new(9, 12)));
+    
+        let mut lexer = Lexer::new("`ab``").quoted_identifiers('`');
+        assert!(lexer.next().is_none());
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::Unterminated { .. })));
+    }
+    
+    #[test]
+    fn line_break_chars_end_lines()
+    {
+        let breaks    = ['\n', '\x1e'];
+        let text      = "a b\x1ec\x1e\n d";
+        let mut lexer = Lexer::new(text).line_break_chars(&breaks);
+        assert_eq!(texts(&mut lexer),
+                   [("a".to_string(), (0, 0)), ("b".to_string(), (0, 2)),
+                    ("c".to_string(), (1, 0)), ("d".to_string(), (3, 1))]);
+    
+        let lexer  = Lexer::new("\"x\x1ey\" z").line_break_chars(&breaks);
+        let tokens = lexer.collect::<Vec<_>>();
+        assert_eq!(tokens[1].pos(), (1, 3));
+    }
+    
+    #[test]
+    fn line_break_chars_end_comments_and_heredocs()
+    {
+        let mut lexer = Lexer::new("a # c\x1eb\x1ec").line_comments(&["#"])
+                                                   .line_break_chars(&['\x1e']);
+        assert_eq!(texts(&mut lexer),
+                   [("a".to_string(), (0, 0)), ("b".to_string(), (1, 0)),
+                    ("c".to_string(), (2, 0))]);
+    
+        let lexer = Lexer::new("<<E\x1ehi\x1eE\x1ex").heredocs(true)
+                                                 .line_break_chars(&['\x1e']);
+        assert_eq!(types(lexer),
+                   pairs(&[(HeredocStart, "<<E"), (Heredoc, "hi\x1e"),
+                           (HeredocEnd, "E"), (Identifier, "x")]));
+    
+        let lexer = Lexer::new("a \\\x1eb").line_continuation_char('\\')
+                                         .line_break_chars(&['\x1e']);
+        assert_eq!(kinds(lexer), [Identifier, Identifier]);
+    }
+    
+    #[test]
+    fn max_bracket_depth_limit_stops_deep_nesting()
+    {
+        let mut lexer = Lexer::new("((([[x]])))").max_bracket_depth_limit(4);
+        assert_eq!(lexer.by_ref().count(), 4);
+        assert!(matches!(lexer.status(),
+                         LexerStatus::Error(LexerError::NestingTooDeep {
+                             line: 0, col: 4, limit: 4, .. })));
+    
+        let lexer = Lexer::new("(()) (()) ((()))").max_bracket_depth_limit(3);
+        assert_eq!(lexer.count(), 14);
+    }
+    
+    #[test]
+    fn word_mode_ends_words_at_any_whitespace()
+    {
+        for text in ["a\u{a0}b", "a\x0cb", "a\u{2028}b"] {
+            let mut lexer = Lexer::new(text).word_mode(true);
+            let words     = (&mut lexer).take(4)
+                                        .map(|t| t.text().to_string())
+                                        .collect::<Vec<_>>();
+            assert_eq!(words, ["a", "b"], "{:?}", text);
+            assert!(matches!(lexer.status(), LexerStatus::EndOfStream));
+        }
+    }
+    
+    #[test]
+    fn line_continuation_does_not_start_a_line()
+    {
+        let lexer = Lexer::new("x = 1 \\\n  y\ny")
+                        .line_continuation_char('\\')
+                        .line_start_keywords(&["y"]);
+        assert_eq!(kinds(lexer), [Identifier, Operator, NumericLiteral,
+                                  Identifier, Keyword]);
+    }
+}
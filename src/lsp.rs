@@ -0,0 +1,150 @@
+#![allow(dead_code)]
+
+use std::fmt::Write;
+
+use crate::lexer::*;
+
+
+/// A position in a text document as the Language Server Protocol counts it:
+/// a zero-based line, and a zero-based offset into the line in UTF-16 code
+/// units.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position
+{
+    pub line      : usize,
+    pub character : usize,
+}
+
+/// A range in a text document, from `start` up to but not including `end`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range
+{
+    pub start : Position,
+    pub end   : Position,
+}
+
+/// The LSP diagnostic severities. The discriminants are the protocol's codes.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity
+{
+    Error       = 1,
+    Warning     = 2,
+    Information = 3,
+    Hint        = 4,
+}
+
+/// An LSP-style diagnostic, ready to be published by a language server.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic
+{
+    pub range    : Range,
+    pub severity : Severity,
+    pub message  : String,
+}
+
+impl Diagnostic
+{
+    /// Renders the diagnostic in the protocol's JSON shape,
+    /// `{"range":{"start":{..},"end":{..}},"severity":1,"message":".."}`.
+    ///
+    pub fn to_json(&self) -> String
+    {
+        let pos = |p: &Position| {
+            format!("{{\"line\":{},\"character\":{}}}", p.line, p.character)
+        };
+        format!("{{\"range\":{{\"start\":{},\"end\":{}}},\"severity\":{},\
+                 \"message\":{}}}",
+                pos(&self.range.start),
+                pos(&self.range.end),
+                self.severity as u8,
+                json_string(&self.message))
+    }
+}
+
+impl LexerError
+{
+    /// Converts the error to an LSP diagnostic. `source` is the text that was
+    /// scanned; it's needed to express the error's span in LSP positions.
+    ///
+    pub fn to_diagnostic(&self, source: &str) -> Diagnostic
+    {
+        let span = self.span();
+        Diagnostic {
+            range    : Range {
+                start : lsp_position(source, span.start),
+                end   : lsp_position(source, span.end),
+            },
+            severity : Severity::Error,
+            message  : self.to_string(),
+        }
+    }
+}
+
+/// Converts a byte offset into `source` into an LSP position.
+///
+fn lsp_position(source: &str, offset: usize) -> Position
+{
+    let before = &source[..offset.min(source.len())];
+    let start  = before.rfind('\n').map_or(0, |i| i + 1);
+    Position {
+        line      : before.matches('\n').count(),
+        character : before[start..].encode_utf16().count(),
+    }
+}
+
+/// Quotes and escapes `text` as a JSON string.
+///
+fn json_string(text: &str) -> String
+{
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn first_error(source: &str) -> LexerError
+    {
+        let mut lexer = Lexer::new(source);
+        (&mut lexer).for_each(drop);
+        match lexer.status() {
+            LexerStatus::Error(e) => e.clone(),
+            status                => panic!("no error, {:?}", status),
+        }
+    }
+
+    #[test]
+    fn diagnostic_json_uses_utf16_positions()
+    {
+        let source = "x = 1;\ny = \"a\\q\";";
+        assert_eq!(first_error(source).to_diagnostic(source).to_json(),
+                   r#"{"range":{"start":{"line":1,"character":7},"#.to_owned()
+                   + r#""end":{"line":1,"character":8}},"severity":1,"#
+                   + r#""message":"Invalid escape in string, \"\\q\"."}"#);
+
+        let source = "\"\u{1f600}\" @";
+        let range  = first_error(source).to_diagnostic(source).range;
+        assert_eq!(range.start, Position { line: 0, character: 5 });
+    }
+}
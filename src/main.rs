@@ -1,5 +1,7 @@
 
+mod analysis;
 mod lexer;
+mod lsp;
 use crate::lexer::*;
 
 fn main() {